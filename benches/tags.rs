@@ -0,0 +1,164 @@
+// compares layer resolution with interned tags against the previous string based TagList. Both
+// sides toggle a tag and resolve the layer of every key after each change
+//
+// cargo bench --bench tags
+
+use std::hint::black_box;
+use std::time::{Duration, Instant};
+
+use keypad_test::profile::*;
+use keypad_test::{TagCondition, TagList};
+
+const KEYS: i128 = 100;
+const LAYERS: i128 = 4;
+const ACTIVE_TAGS: i128 = 12;
+const ITERATIONS: u32 = 2_000;
+
+fn main() {
+	let profile = new_bench_profile();
+	let toggled = [tag(3)];
+
+	let mut legacy = LegacyTagList::default();
+	legacy.internal.extend(active_tags());
+	let legacy_time = measure(|| {
+		legacy.internal.extend(toggled.iter().cloned());
+		resolve_legacy(&profile, &legacy);
+		legacy.internal.pop();
+		resolve_legacy(&profile, &legacy);
+	});
+
+	let mut tags = TagList::new();
+	let conditions = compile_conditions(&profile, &mut tags);
+	tags.add_many_internal(active_tags());
+	let interned_time = measure(|| {
+		tags.add_internal(toggled[0].clone());
+		resolve_interned(&profile, &conditions, &tags);
+		tags.remove_internal(toggled[0].clone());
+		resolve_interned(&profile, &conditions, &tags);
+	});
+
+	report("string tags", legacy_time);
+	report("interned tags", interned_time);
+}
+
+// a typical set of modifiers and modes being held, none of them completing a layer
+fn active_tags() -> Vec<LayerTag> {
+	(0..ACTIVE_TAGS)
+		.map(|index| tag(index * 7 + 100))
+		.chain([tag(0)])
+		.collect()
+}
+
+fn measure<F: FnMut()>(mut f: F) -> Duration {
+	for _ in 0..ITERATIONS / 10 {
+		f();
+	}
+
+	let start = Instant::now();
+	for _ in 0..ITERATIONS {
+		f();
+	}
+	start.elapsed()
+}
+
+fn report(name: &str, time: Duration) {
+	println!(
+		"{:<16}{:>10.2} us per tag change",
+		name,
+		time.as_secs_f64() * 1_000_000.0 / (ITERATIONS * 2) as f64
+	);
+}
+
+// conditions per key and layer, compiled once like KeyboardState does when it is created
+fn compile_conditions(profile: &KeyboardProfile, tags: &mut TagList) -> Vec<Vec<TagCondition>> {
+	profile
+		.keys
+		.iter()
+		.map(|key| {
+			key.layers
+				.iter()
+				.map(|layer| layer.compile_condition(tags))
+				.collect()
+		})
+		.collect()
+}
+
+fn resolve_interned(profile: &KeyboardProfile, conditions: &[Vec<TagCondition>], tags: &TagList) {
+	for (key, conditions) in profile.keys.iter().zip(conditions) {
+		let layer = key.find_active_layer(|index, _| conditions[index].evaluate(tags));
+		black_box(layer.id);
+	}
+}
+
+fn resolve_legacy(profile: &KeyboardProfile, tags: &LegacyTagList) {
+	for key in profile.keys.iter() {
+		let layer = key.find_active_layer(|_, layer| match layer.match_type {
+			TagMatchType::Any => tags.contains_any(&layer.tags),
+			_ => tags.contains_all(&layer.tags),
+		});
+		black_box(layer.id);
+	}
+}
+
+// the TagList from before tags were interned
+#[derive(Default)]
+struct LegacyTagList {
+	internal: Vec<LayerTag>,
+	external: Vec<LayerTag>,
+}
+
+impl LegacyTagList {
+	fn contains_all(&self, tags: &[LayerTag]) -> bool {
+		tags.iter()
+			.all(|tag| self.internal.contains(tag) || self.external.contains(tag))
+	}
+
+	fn contains_any(&self, tags: &[LayerTag]) -> bool {
+		tags.iter()
+			.any(|tag| self.internal.contains(tag) || self.external.contains(tag))
+	}
+}
+
+fn new_bench_profile() -> KeyboardProfile {
+	KeyboardProfile {
+		keys: (0..KEYS).map(new_bench_key).collect(),
+		library: Vec::new(),
+		channels: Vec::new(),
+		stop_all_clears_tags: false,
+		internal_tag_mode: InternalTagMode::Counted,
+		tag_triggers: Vec::new(),
+		startup_macro: None,
+		idle: Vec::new(),
+	}
+}
+
+fn new_bench_key(id: i128) -> DeviceKey {
+	DeviceKey {
+		key_id: KeyId::new(id),
+		layers: (0..LAYERS)
+			.map(|layer| TaggedDeviceKeyLayer {
+				layer: new_bench_layer(layer + 2),
+				tags: vec![tag(layer), tag(layer + 1), tag(layer + 4)],
+				match_type: match layer % 2 {
+					0 => TagMatchType::All,
+					_ => TagMatchType::Any,
+				},
+				priority: 0,
+				mode: LayerMode::Opaque,
+			})
+			.collect(),
+		default_layer: new_bench_layer(1),
+	}
+}
+
+fn new_bench_layer(id: i128) -> DeviceKeyLayer {
+	DeviceKeyLayer {
+		id: LayerId::new(id),
+		macros: Vec::new(),
+		press_macros: Vec::new(),
+	}
+}
+
+fn tag(index: i128) -> LayerTag {
+	LayerTag::new(format!("layer-tag-{}", index))
+}
@@ -3,37 +3,163 @@
 extern crate alloc;
 extern crate serde_json_core;
 
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
 use alloc::vec::Vec;
-use profile::LayerTag;
+use profile::{InternalTagMode, LayerTag, PushMode};
 
+pub mod owned;
 pub mod profile;
+pub mod rng;
+pub mod sink;
 pub mod state;
+pub mod tag_expr;
 
+// conditions on tags past this many can't be compiled and never match
+pub const MAX_TAGS: usize = 1024;
+
+#[derive(Default)]
 pub struct TagList {
-	internal: Vec<LayerTag>,
-	external: Vec<LayerTag>,
+	ids: BTreeMap<LayerTag, TagId>,
+	names: Vec<LayerTag>,
+	internal: TagSet,
+	// per id, see InternalTagMode for how sets and clears pair up
+	internal_counts: Vec<u16>,
+	internal_mode: InternalTagMode,
+	external: TagSet,
+	// apart from the internal counts, so a hold running out never clears a tag set some other way
+	timed: TagSet,
+	stack: Vec<(LayerTag, PushMode)>,
+	stacked: TagSet,
+	others: OtherTags,
 }
 
 impl TagList {
 	pub fn new() -> Self {
-		TagList {
-			internal: Vec::new(),
-			external: Vec::new(),
+		TagList::default()
+	}
+
+	// for profile conditions only. Ids are never reused, so anything compiled against this list
+	// stays valid
+	pub fn intern(&mut self, tag: &LayerTag) -> Option<TagId> {
+		if let Some(id) = self.ids.get(tag) {
+			return Some(*id);
+		}
+		if self.names.len() >= MAX_TAGS {
+			return None;
+		}
+
+		let id = TagId(self.names.len() as u16);
+		self.ids.insert(tag.clone(), id);
+		self.names.push(tag.clone());
+		self.adopt(tag, id);
+		Some(id)
+	}
+
+	// a tag that was already active by name stays active under its new id
+	fn adopt(&mut self, tag: &LayerTag, id: TagId) {
+		if let Some(index) = self
+			.others
+			.internal
+			.iter()
+			.position(|(other, _)| other == tag)
+		{
+			let (_, count) = self.others.internal.swap_remove(index);
+			*self.internal_count_mut(id) = count;
+			self.internal.insert(id);
+		}
+		if remove_tag(&mut self.others.external, tag) {
+			self.external.insert(id);
+		}
+		if remove_tag(&mut self.others.timed, tag) {
+			self.timed.insert(id);
 		}
+		if self.stack.iter().any(|(other, _)| other == tag) {
+			self.update_stacked();
+		}
+	}
+
+	pub fn id_of(&self, tag: &LayerTag) -> Option<TagId> {
+		self.ids.get(tag).copied()
+	}
+
+	pub fn name_of(&self, id: TagId) -> Option<&LayerTag> {
+		self.names.get(id.0 as usize)
+	}
+
+	pub fn set_internal_mode(&mut self, mode: InternalTagMode) {
+		self.internal_mode = mode;
 	}
 
 	pub fn add_internal(&mut self, tag: LayerTag) {
-		self.internal.push(tag);
+		let mode = self.internal_mode;
+		let count = match self.id_of(&tag) {
+			Some(id) => {
+				self.internal.insert(id);
+				self.internal_count_mut(id)
+			}
+			None => self.others.internal_count_mut(tag),
+		};
+
+		*count = match mode {
+			InternalTagMode::Counted => count.saturating_add(1),
+			InternalTagMode::Idempotent => 1,
+		};
 	}
 
 	pub fn add_many_internal(&mut self, tags: Vec<LayerTag>) {
-		self.internal.extend(tags);
+		for tag in tags {
+			self.add_internal(tag);
+		}
 	}
 
 	pub fn remove_internal(&mut self, tag: LayerTag) {
-		if let Some(index) = self.internal.iter().position(|t| *t == tag) {
-			self.internal.remove(index);
+		let mode = self.internal_mode;
+		let count = match self.id_of(&tag) {
+			Some(id) => self.internal_counts.get_mut(id.0 as usize),
+			None => self
+				.others
+				.internal
+				.iter_mut()
+				.find(|(other, _)| *other == tag)
+				.map(|(_, count)| count),
+		};
+		let Some(count) = count else {
+			return;
+		};
+
+		*count = match mode {
+			InternalTagMode::Counted => count.saturating_sub(1),
+			InternalTagMode::Idempotent => 0,
+		};
+		if *count > 0 {
+			return;
 		}
+		match self.id_of(&tag) {
+			Some(id) => self.internal.remove(id),
+			None => self.others.internal.retain(|(other, _)| *other != tag),
+		}
+	}
+
+	pub fn internal_count(&self, tag: &LayerTag) -> u16 {
+		match self.id_of(tag) {
+			Some(id) => self.internal_counts.get(id.0 as usize).copied(),
+			None => self
+				.others
+				.internal
+				.iter()
+				.find(|(other, _)| other == tag)
+				.map(|(_, count)| *count),
+		}
+		.unwrap_or(0)
+	}
+
+	fn internal_count_mut(&mut self, id: TagId) -> &mut u16 {
+		let index = id.0 as usize;
+		if self.internal_counts.len() <= index {
+			self.internal_counts.resize(index + 1, 0);
+		}
+		&mut self.internal_counts[index]
 	}
 
 	pub fn remove_many_internal(&mut self, tags: Vec<LayerTag>) {
@@ -42,24 +168,524 @@ impl TagList {
 		}
 	}
 
+	// also empties the tag stack and drops the timed holds
 	pub fn clear_internal(&mut self) {
 		self.internal.clear();
+		self.internal_counts.clear();
+		self.timed.clear();
+		self.stack.clear();
+		self.stacked.clear();
+		self.others.internal.clear();
+		self.others.timed.clear();
+	}
+
+	pub fn push(&mut self, tag: LayerTag, mode: PushMode) {
+		self.stack.push((tag, mode));
+		self.update_stacked();
+	}
+
+	pub fn pop(&mut self) -> Option<LayerTag> {
+		let (tag, _) = self.stack.pop()?;
+		self.update_stacked();
+		Some(tag)
+	}
+
+	// every tag with an id that is currently active, from any source
+	pub fn active(&self) -> TagSet {
+		let len = self
+			.internal
+			.words
+			.len()
+			.max(self.external.words.len())
+			.max(self.timed.words.len())
+			.max(self.stacked.words.len());
+
+		TagSet {
+			words: (0..len).map(|index| self.active_word(index)).collect(),
+		}
+	}
+
+	// the active tags without an id, sorted
+	pub fn active_others(&self) -> Vec<LayerTag> {
+		let mut active: Vec<LayerTag> = self
+			.others
+			.internal
+			.iter()
+			.map(|(tag, _)| tag)
+			.chain(self.others.external.iter())
+			.chain(self.others.timed.iter())
+			.chain(self.visible_stack().filter(|tag| self.id_of(tag).is_none()))
+			.cloned()
+			.collect();
+		active.sort();
+		active.dedup();
+		active
+	}
+
+	pub fn stack_depth(&self) -> usize {
+		self.stack.len()
+	}
+
+	// everything down to and including the topmost exclusive push
+	fn visible_stack(&self) -> impl Iterator<Item = &LayerTag> {
+		let hidden = self
+			.stack
+			.iter()
+			.rposition(|(_, mode)| *mode == PushMode::Exclusive)
+			.unwrap_or(0);
+		self.stack[hidden..].iter().map(|(tag, _)| tag)
+	}
+
+	fn update_stacked(&mut self) {
+		let mut stacked = TagSet::default();
+		for id in self.visible_stack().filter_map(|tag| self.id_of(tag)) {
+			stacked.insert(id);
+		}
+		self.stacked = stacked;
 	}
 
 	pub fn set_external(&mut self, tags: Vec<LayerTag>) {
-		self.external = tags;
+		self.external.clear();
+		self.others.external.clear();
+		for tag in tags {
+			match self.id_of(&tag) {
+				Some(id) => self.external.insert(id),
+				None => insert_tag(&mut self.others.external, tag),
+			}
+		}
+	}
+
+	// every tag with a timed hold that hasn't run out yet
+	pub fn set_timed<'t>(&mut self, tags: impl IntoIterator<Item = &'t LayerTag>) {
+		self.timed.clear();
+		self.others.timed.clear();
+		for tag in tags {
+			match self.id_of(tag) {
+				Some(id) => self.timed.insert(id),
+				None => insert_tag(&mut self.others.timed, tag.clone()),
+			}
+		}
+	}
+
+	pub fn contains(&self, tag: &LayerTag) -> bool {
+		match self.id_of(tag) {
+			Some(id) => self.contains_id(id),
+			None => self.others.contains(tag) || self.visible_stack().any(|other| other == tag),
+		}
+	}
+
+	pub fn contains_id(&self, id: TagId) -> bool {
+		self.internal.contains(id)
+			|| self.external.contains(id)
+			|| self.timed.contains(id)
+			|| self.stacked.contains(id)
+	}
+
+	pub fn contains_all(&self, tags: &[LayerTag]) -> bool {
+		tags.iter().all(|tag| self.contains(tag))
+	}
+
+	pub fn contains_any(&self, tags: &[LayerTag]) -> bool {
+		tags.iter().any(|tag| self.contains(tag))
+	}
+
+	fn contains_all_ids(&self, set: &TagSet) -> bool {
+		set.words
+			.iter()
+			.enumerate()
+			.all(|(index, word)| word & !self.active_word(index) == 0)
+	}
+
+	fn contains_any_ids(&self, set: &TagSet) -> bool {
+		set.words
+			.iter()
+			.enumerate()
+			.any(|(index, word)| word & self.active_word(index) != 0)
 	}
 
-	pub fn contains_all(&self, tags: &Vec<LayerTag>) -> bool {
-		tags.iter()
-			.all(|tag| self.internal.contains(tag) || self.external.contains(tag))
+	fn active_word(&self, index: usize) -> u64 {
+		self.internal.word(index)
+			| self.external.word(index)
+			| self.timed.word(index)
+			| self.stacked.word(index)
 	}
+}
+
+// active tags that no profile condition refers to, kept by name. They can't change a layer, so
+// they don't take up ids
+#[derive(Default)]
+struct OtherTags {
+	internal: Vec<(LayerTag, u16)>,
+	external: Vec<LayerTag>,
+	timed: Vec<LayerTag>,
+}
 
-	pub fn contains_any(&self, tags: &Vec<LayerTag>) -> bool {
-		tags.iter()
-			.any(|tag| self.internal.contains(tag) || self.external.contains(tag))
+impl OtherTags {
+	fn internal_count_mut(&mut self, tag: LayerTag) -> &mut u16 {
+		let index = match self.internal.iter().position(|(other, _)| *other == tag) {
+			Some(index) => index,
+			None => {
+				self.internal.push((tag, 0));
+				self.internal.len() - 1
+			}
+		};
+		&mut self.internal[index].1
+	}
+
+	fn contains(&self, tag: &LayerTag) -> bool {
+		self.internal.iter().any(|(other, _)| other == tag)
+			|| self.external.contains(tag)
+			|| self.timed.contains(tag)
+	}
+}
+
+fn insert_tag(tags: &mut Vec<LayerTag>, tag: LayerTag) {
+	if !tags.contains(&tag) {
+		tags.push(tag);
+	}
+}
+
+fn remove_tag(tags: &mut Vec<LayerTag>, tag: &LayerTag) -> bool {
+	let len = tags.len();
+	tags.retain(|other| other != tag);
+	tags.len() != len
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct TagId(u16);
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct TagSet {
+	words: Vec<u64>,
+}
+
+impl TagSet {
+	pub fn insert(&mut self, id: TagId) {
+		let (index, bit) = TagSet::position(id);
+		if self.words.len() <= index {
+			self.words.resize(index + 1, 0);
+		}
+		self.words[index] |= bit;
+	}
+
+	pub fn remove(&mut self, id: TagId) {
+		let (index, bit) = TagSet::position(id);
+		if let Some(word) = self.words.get_mut(index) {
+			*word &= !bit;
+		}
+	}
+
+	pub fn contains(&self, id: TagId) -> bool {
+		let (index, bit) = TagSet::position(id);
+		self.word(index) & bit != 0
+	}
+
+	pub fn clear(&mut self) {
+		self.words.clear();
+	}
+
+	// ids in this set that are not in the other
+	pub fn difference<'s>(&'s self, other: &'s TagSet) -> impl Iterator<Item = TagId> + 's {
+		self.words
+			.iter()
+			.enumerate()
+			.flat_map(move |(index, word)| {
+				let word = word & !other.word(index);
+				(0..64)
+					.filter(move |bit| word & (1 << bit) != 0)
+					.map(move |bit| TagId((index * 64 + bit) as u16))
+			})
+	}
+
+	fn word(&self, index: usize) -> u64 {
+		self.words.get(index).copied().unwrap_or(0)
+	}
+
+	fn position(id: TagId) -> (usize, u64) {
+		(id.0 as usize / 64, 1 << (id.0 % 64))
+	}
+}
+
+// a layer condition resolved against the interned ids of a TagList
+#[derive(Debug, PartialEq)]
+pub enum TagCondition {
+	All(TagSet),
+	Any(TagSet),
+	Tag(TagId),
+	Not(Box<TagCondition>),
+	And(Vec<TagCondition>),
+	Or(Vec<TagCondition>),
+}
+
+impl TagCondition {
+	pub fn evaluate(&self, tags: &TagList) -> bool {
+		match self {
+			TagCondition::All(set) => tags.contains_all_ids(set),
+			TagCondition::Any(set) => tags.contains_any_ids(set),
+			TagCondition::Tag(id) => tags.contains_id(*id),
+			TagCondition::Not(condition) => !condition.evaluate(tags),
+			TagCondition::And(conditions) => conditions.iter().all(|c| c.evaluate(tags)),
+			TagCondition::Or(conditions) => conditions.iter().any(|c| c.evaluate(tags)),
+		}
 	}
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests {
+	use super::*;
+	use alloc::string::ToString;
+	use alloc::vec;
+
+	#[test]
+	fn interning_is_stable() {
+		let mut tags = TagList::new();
+		let first = tags.intern(&tag("a")).unwrap();
+		let second = tags.intern(&tag("b")).unwrap();
+
+		assert_eq!(tags.intern(&tag("a")), Some(first));
+		assert_ne!(first, second);
+		assert_eq!(tags.name_of(second), Some(&tag("b")));
+	}
+
+	#[test]
+	fn contains_checks_internal_and_external() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("a"));
+		tags.set_external(vec![tag("b")]);
+
+		assert!(tags.contains_all(&[tag("a"), tag("b")]));
+		assert!(tags.contains_any(&[tag("c"), tag("b")]));
+		assert!(!tags.contains_any(&[tag("c")]));
+
+		tags.remove_internal(tag("a"));
+		tags.set_external(vec![]);
+		assert!(!tags.contains_any(&[tag("a"), tag("b")]));
+	}
+
+	#[test]
+	fn counted_tags_stay_until_every_set_is_cleared() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("a"));
+		tags.add_internal(tag("a"));
+		assert_eq!(tags.internal_count(&tag("a")), 2);
+
+		tags.remove_internal(tag("a"));
+		assert!(tags.contains(&tag("a")));
+
+		tags.remove_internal(tag("a"));
+		assert!(!tags.contains(&tag("a")));
+
+		// extra clears don't go negative
+		tags.remove_internal(tag("a"));
+		tags.add_internal(tag("a"));
+		assert!(tags.contains(&tag("a")));
+	}
+
+	#[test]
+	fn idempotent_tags_clear_on_first_remove() {
+		let mut tags = TagList::new();
+		tags.set_internal_mode(InternalTagMode::Idempotent);
+		tags.add_internal(tag("a"));
+		tags.add_internal(tag("a"));
+		assert_eq!(tags.internal_count(&tag("a")), 1);
+
+		tags.remove_internal(tag("a"));
+		assert!(!tags.contains(&tag("a")));
+	}
+
+	#[test]
+	fn clearing_internal_tags_resets_counts() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("a"));
+		tags.add_internal(tag("a"));
+		tags.clear_internal();
+		tags.add_internal(tag("a"));
+		tags.remove_internal(tag("a"));
+
+		assert!(!tags.contains(&tag("a")));
+	}
+
+	#[test]
+	fn timed_tags_are_apart_from_internal_counts() {
+		let mut tags = TagList::new();
+		tags.set_internal_mode(InternalTagMode::Idempotent);
+		tags.add_internal(tag("a"));
+		tags.set_timed([&tag("a")]);
+
+		tags.remove_internal(tag("a"));
+		assert!(tags.contains(&tag("a")));
+
+		tags.set_timed([]);
+		assert!(!tags.contains(&tag("a")));
+	}
+
+	#[test]
+	fn overlay_push_keeps_lower_stack_tags() {
+		let mut tags = TagList::new();
+		tags.push(tag("normal"), PushMode::Overlay);
+		tags.push(tag("visual"), PushMode::Overlay);
+
+		assert!(tags.contains_all(&[tag("normal"), tag("visual")]));
+	}
+
+	#[test]
+	fn exclusive_push_hides_lower_stack_tags() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("caps"));
+		tags.push(tag("normal"), PushMode::Overlay);
+		tags.push(tag("insert"), PushMode::Exclusive);
+		tags.push(tag("shift"), PushMode::Overlay);
+
+		assert!(tags.contains_all(&[tag("caps"), tag("insert"), tag("shift")]));
+		assert!(!tags.contains(&tag("normal")));
+	}
+
+	#[test]
+	fn pop_restores_previous_state() {
+		let mut tags = TagList::new();
+		tags.push(tag("normal"), PushMode::Overlay);
+		tags.push(tag("insert"), PushMode::Exclusive);
+
+		assert_eq!(tags.pop(), Some(tag("insert")));
+		assert!(tags.contains(&tag("normal")));
+		assert!(!tags.contains(&tag("insert")));
+
+		assert_eq!(tags.pop(), Some(tag("normal")));
+		assert_eq!(tags.pop(), None);
+		assert_eq!(tags.stack_depth(), 0);
+	}
+
+	#[test]
+	fn pushed_tag_outlives_internal_clear_of_same_tag() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("a"));
+		tags.push(tag("a"), PushMode::Overlay);
+		tags.remove_internal(tag("a"));
+
+		assert!(tags.contains(&tag("a")));
+	}
+
+	#[test]
+	fn difference_lists_ids_missing_from_other() {
+		let mut tags = TagList::new();
+		let ids: Vec<TagId> = (0..70)
+			.map(|index| tags.intern(&tag(&index.to_string())).unwrap())
+			.collect();
+		let mut set = TagSet::default();
+		let mut other = TagSet::default();
+		set.insert(ids[1]);
+		set.insert(ids[3]);
+		set.insert(ids[69]);
+		other.insert(ids[3]);
+
+		assert_eq!(
+			set.difference(&other).collect::<Vec<_>>(),
+			vec![ids[1], ids[69]]
+		);
+		assert_eq!(other.difference(&set).count(), 0);
+	}
+
+	#[test]
+	fn conditions_span_multiple_words() {
+		let mut tags = TagList::new();
+		let mut set = TagSet::default();
+		for index in 0..100 {
+			set.insert(tags.intern(&tag(&index.to_string())).unwrap());
+		}
+		let all = TagCondition::All(set.clone());
+		let any = TagCondition::Any(set);
+
+		tags.add_internal(tag("99"));
+		assert!(any.evaluate(&tags));
+		assert!(!all.evaluate(&tags));
+
+		for index in 0..99 {
+			tags.add_internal(tag(&index.to_string()));
+		}
+		assert!(all.evaluate(&tags));
+	}
+
+	#[test]
+	fn empty_conditions_match_like_lists() {
+		let tags = TagList::new();
+
+		assert!(TagCondition::All(TagSet::default()).evaluate(&tags));
+		assert!(!TagCondition::Any(TagSet::default()).evaluate(&tags));
+	}
+
+	#[test]
+	fn interning_stops_at_max_tags() {
+		let mut tags = TagList::new();
+		for index in 0..MAX_TAGS {
+			assert!(tags.intern(&tag(&index.to_string())).is_some());
+		}
+
+		assert_eq!(tags.intern(&tag("extra")), None);
+		assert!(tags.intern(&tag("0")).is_some());
+
+		// a tag without an id is still active by name
+		tags.add_internal(tag("extra"));
+		assert!(tags.contains(&tag("extra")));
+		assert_eq!(tags.id_of(&tag("extra")), None);
+	}
+
+	#[test]
+	fn pushing_a_tag_past_max_tags_still_pops() {
+		let mut tags = TagList::new();
+		for index in 0..MAX_TAGS {
+			tags.intern(&tag(&index.to_string()));
+		}
+		tags.push(tag("0"), PushMode::Overlay);
+		tags.push(tag("extra"), PushMode::Exclusive);
+		assert!(!tags.contains(&tag("0")));
+		assert!(tags.contains(&tag("extra")));
+
+		assert_eq!(tags.pop(), Some(tag("extra")));
+		assert_eq!(tags.stack_depth(), 1);
+		assert!(tags.contains(&tag("0")));
+	}
+
+	#[test]
+	fn runtime_tags_take_no_ids() {
+		let mut tags = TagList::new();
+		for index in 0..2 * MAX_TAGS {
+			let name = tag(&index.to_string());
+			tags.add_internal(name.clone());
+			tags.set_external(vec![name.clone()]);
+			tags.push(name, PushMode::Overlay);
+		}
+
+		assert!(tags.intern(&tag("vim")).is_some());
+		tags.set_external(vec![tag("vim")]);
+		assert!(TagCondition::Tag(tags.id_of(&tag("vim")).unwrap()).evaluate(&tags));
+	}
+
+	#[test]
+	fn active_tags_keep_their_state_when_interned() {
+		let mut tags = TagList::new();
+		tags.add_internal(tag("a"));
+		tags.add_internal(tag("a"));
+		tags.set_external(vec![tag("b")]);
+		tags.set_timed([&tag("c")]);
+		tags.push(tag("d"), PushMode::Overlay);
+		assert_eq!(
+			tags.active_others(),
+			vec![tag("a"), tag("b"), tag("c"), tag("d")]
+		);
+
+		let ids: Vec<TagId> = ["a", "b", "c", "d"]
+			.iter()
+			.map(|name| tags.intern(&tag(name)).unwrap())
+			.collect();
+		assert!(ids.iter().all(|id| tags.active().contains(*id)));
+		assert!(tags.active_others().is_empty());
+
+		tags.remove_internal(tag("a"));
+		assert!(tags.contains(&tag("a")));
+		assert_eq!(tags.internal_count(&tag("a")), 1);
+	}
+
+	fn tag(name: &str) -> LayerTag {
+		LayerTag::new(name.to_string())
+	}
+}
@@ -0,0 +1,193 @@
+use crate::profile::KeyboardProfile;
+use crate::state::KeyboardState;
+
+// a KeyboardState that owns its profile, so a freshly loaded profile can replace the old one
+// without anything having to outlive the state
+pub type OwnedKeyboardState = KeyboardState<KeyboardProfile>;
+
+#[cfg(test)]
+mod tests {
+	extern crate alloc;
+
+	use super::*;
+	use crate::profile::*;
+	use crate::sink::OutputEvent;
+	use crate::state::{Notification, ProfileUpdate};
+	use alloc::string::ToString;
+	use alloc::vec;
+	use alloc::vec::Vec;
+
+	#[test]
+	fn macros_keep_playing_between_calls() {
+		let mut state = OwnedKeyboardState::from(new_test_profile(new_test_macro(
+			MacroId::new(1),
+			KeyboardKey::A,
+		)));
+
+		state.press_key(KeyId::new(1));
+		let mut events: Vec<OutputEvent> = Vec::new();
+		state.tick(100, &mut events);
+		assert!(matches!(
+			events[..],
+			[OutputEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::A
+			))]
+		));
+
+		state.release_key(KeyId::new(1));
+		let mut events: Vec<OutputEvent> = Vec::new();
+		state.tick(100, &mut events);
+		assert!(matches!(
+			events[..],
+			[OutputEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))]
+		));
+		assert_eq!(state.next_deadline_ms(), None);
+	}
+
+	#[test]
+	fn updated_profile_replaces_the_old_one() {
+		let mut state = OwnedKeyboardState::from(new_test_profile(new_test_macro(
+			MacroId::new(1),
+			KeyboardKey::A,
+		)));
+
+		state.update_key_profile(new_test_profile(new_test_macro(
+			MacroId::new(2),
+			KeyboardKey::B,
+		)));
+		state.press_key(KeyId::new(1));
+		let mut events: Vec<OutputEvent> = Vec::new();
+		state.tick(100, &mut events);
+
+		assert!(matches!(
+			events[..],
+			[OutputEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::B
+			))]
+		));
+	}
+
+	#[test]
+	fn macros_of_the_old_profile_finish_from_it() {
+		let mut state = OwnedKeyboardState::from(new_test_profile(new_test_macro(
+			MacroId::new(1),
+			KeyboardKey::A,
+		)));
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut Vec::new());
+
+		state.update_key_profile(new_test_profile(new_test_macro(
+			MacroId::new(2),
+			KeyboardKey::B,
+		)));
+		state.tick(0, &mut Vec::new());
+		assert_eq!(state.next_deadline_ms(), Some(100));
+		let mut events: Vec<OutputEvent> = Vec::new();
+		state.tick(100, &mut events);
+
+		assert!(matches!(
+			events[..],
+			[OutputEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))]
+		));
+		assert_eq!(state.next_deadline_ms(), None);
+	}
+
+	#[test]
+	fn unchanged_macros_outlive_the_old_profile() {
+		let mut state = OwnedKeyboardState::from(new_test_profile(new_test_macro(
+			MacroId::new(1),
+			KeyboardKey::A,
+		)));
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut Vec::new());
+
+		state.update_key_profile_with(
+			new_test_profile(new_test_macro(MacroId::new(1), KeyboardKey::A)),
+			ProfileUpdate::KeepUnchangedMacros,
+		);
+		state.tick(0, &mut Vec::new());
+		assert_eq!(state.next_deadline_ms(), None);
+
+		state.release_key(KeyId::new(1));
+		let mut events: Vec<OutputEvent> = Vec::new();
+		state.tick(100, &mut events);
+		assert!(matches!(
+			events[..],
+			[OutputEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))]
+		));
+	}
+
+	#[test]
+	fn tags_are_kept_between_calls() {
+		let mut state = OwnedKeyboardState::from(new_test_profile(new_test_macro(
+			MacroId::new(1),
+			KeyboardKey::A,
+		)));
+		let tag = LayerTag::new("test".to_string());
+		state.enable_notifications();
+
+		state.add_internal_tags(vec![tag.clone()]);
+		state.add_internal_tags(vec![tag.clone()]);
+		state.remove_internal_tags(vec![tag.clone()]);
+
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![Notification::TagAdded(tag)]
+		);
+	}
+
+	// ------- HELPERS --------
+
+	fn new_test_profile(macro_: Macro) -> KeyboardProfile {
+		KeyboardProfile {
+			keys: vec![DeviceKey {
+				key_id: KeyId::new(1),
+				layers: Vec::new(),
+				default_layer: DeviceKeyLayer {
+					id: LayerId::new(1),
+					macros: vec![macro_.into()],
+					press_macros: Vec::new(),
+				},
+			}],
+			library: Vec::new(),
+			channels: Vec::new(),
+			stop_all_clears_tags: false,
+			internal_tag_mode: InternalTagMode::Counted,
+			tag_triggers: Vec::new(),
+			startup_macro: None,
+			idle: Vec::new(),
+		}
+	}
+
+	// holds key down while the key is held
+	fn new_test_macro(id: MacroId, key: KeyboardKey) -> Macro {
+		Macro {
+			id,
+			name: "Name".to_string(),
+			play_channel: None,
+			cut_channels: Vec::new(),
+			start_sequence: Sequence {
+				actions: vec![Action {
+					predelay_ms: 100,
+					jitter_ms: None,
+					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyDown(key)),
+				}],
+			},
+			loop_sequence: Sequence {
+				actions: Vec::new(),
+			},
+			end_sequence: Sequence {
+				actions: vec![Action {
+					predelay_ms: 100,
+					jitter_ms: None,
+					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyUp(key)),
+				}],
+			},
+			max_loops: None,
+			max_loop_ms: None,
+			trigger_mode: TriggerMode::Hold,
+			speed_multiplier: None,
+			cut_mode: StopMode::Graceful,
+		}
+	}
+}
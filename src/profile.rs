@@ -4,10 +4,166 @@ extern crate serde_json_core;
 use alloc::string::String;
 use alloc::vec::Vec;
 
-use crate::TagList;
+use crate::tag_expr::TagExpr;
+use crate::{TagCondition, TagList, TagSet};
 
 pub struct KeyboardProfile {
 	pub keys: Vec<DeviceKey>,
+	pub library: Vec<Macro>,
+	pub channels: Vec<ChannelConfig>,
+	// whether stopping all macros also clears the internal tags
+	pub stop_all_clears_tags: bool,
+	pub internal_tag_mode: InternalTagMode,
+	pub tag_triggers: Vec<TagTrigger>,
+	// played on the first tick after the profile is loaded
+	pub startup_macro: Option<MacroRef>,
+	pub idle: Vec<IdleAction>,
+}
+
+impl KeyboardProfile {
+	pub fn find_library_macro(&self, id: MacroId) -> Option<&Macro> {
+		self.library.iter().find(|macro_| macro_.id == id)
+	}
+
+	// includes the press duration macros of the layer
+	pub fn find_key_macro(&self, key: KeyId, layer: LayerId, id: MacroId) -> Option<MacroLoc> {
+		let key_index = self
+			.keys
+			.iter()
+			.position(|device_key| device_key.key_id == key)?;
+		let (layer_index, key_layer) = self.keys[key_index]
+			.all_layers()
+			.enumerate()
+			.find(|(_, key_layer)| key_layer.id == layer)?;
+
+		key_layer
+			.all_macros()
+			.enumerate()
+			.filter_map(|(index, macro_ref)| {
+				MacroLoc::of(
+					macro_ref,
+					MacroLoc::Key(key_index, layer_index, index),
+					self,
+				)
+			})
+			.find(|(_, macro_)| macro_.id == id)
+			.map(|(loc, _)| loc)
+	}
+
+	// channels without an entry keep the default cut behavior
+	pub fn channel_policy(&self, channel: Channel) -> ChannelPolicy {
+		self.channels
+			.iter()
+			.find(|config| config.channel == channel)
+			.map(|config| config.policy)
+			.unwrap_or(ChannelPolicy::Cut)
+	}
+
+	pub fn validate(&self) -> Result<(), ProfileError> {
+		let key_macros = self
+			.keys
+			.iter()
+			.flat_map(|key| key.all_layers())
+			.flat_map(|layer| layer.all_macros());
+		let trigger_macros = self.tag_triggers.iter().map(|trigger| &trigger.macro_);
+		let idle_macros = self.idle.iter().filter_map(|idle| idle.macro_.as_ref());
+
+		for macro_ref in key_macros
+			.chain(trigger_macros)
+			.chain(idle_macros)
+			.chain(self.startup_macro.as_ref())
+		{
+			match macro_ref {
+				MacroRef::Inline(macro_) => {
+					for id in macro_.called_macros() {
+						self.check_calls(id, &mut Vec::new())?;
+					}
+				}
+				MacroRef::Library(id) => self.check_calls(*id, &mut Vec::new())?,
+			}
+		}
+
+		for macro_ in self.library.iter() {
+			self.check_calls(macro_.id, &mut Vec::new())?;
+		}
+
+		Ok(())
+	}
+
+	// depth-first walk of the call graph, `path` holds the macros currently being called
+	fn check_calls(&self, id: MacroId, path: &mut Vec<MacroId>) -> Result<(), ProfileError> {
+		if path.contains(&id) {
+			return Err(ProfileError::CallCycle(id));
+		}
+
+		let macro_ = self
+			.find_library_macro(id)
+			.ok_or(ProfileError::UnknownMacro(id))?;
+
+		path.push(id);
+		for callee in macro_.called_macros() {
+			self.check_calls(callee, path)?;
+		}
+		path.pop();
+
+		Ok(())
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ProfileError {
+	UnknownMacro(MacroId),
+	CallCycle(MacroId),
+}
+
+pub struct ChannelConfig {
+	pub channel: Channel,
+	pub policy: ChannelPolicy,
+}
+
+// plays a macro when the condition starts or stops matching, the macro is released again once
+// the condition goes back
+pub struct TagTrigger {
+	pub condition: TagExpr,
+	pub edge: TriggerEdge,
+	pub macro_: MacroRef,
+}
+
+// after this long without a key press the macro plays and the tags are set, both are undone
+// by the next press
+pub struct IdleAction {
+	pub timeout_ms: u32,
+	pub macro_: Option<MacroRef>,
+	pub tags: Vec<LayerTag>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerEdge {
+	Enter,
+	Exit,
+}
+
+// how repeated sets of the same internal tag are counted, timed sets hold their tag apart from this
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum InternalTagMode {
+	// every set needs a matching clear, a tag set by two macros stays until both clear it
+	#[default]
+	Counted,
+	// setting an active tag does nothing and a single clear removes it
+	Idempotent,
+}
+
+// decides what happens to a new macro whose play channel is already in use
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChannelPolicy {
+	// play alongside, other macros are stopped through cut channels
+	Cut,
+	// wait until every macro on the channel has finished
+	Queue,
+	// drop the new macro
+	IgnoreNew,
+	// drop the new macro once this many are playing on the channel
+	MaxConcurrent(u32),
 }
 
 pub struct DeviceKey {
@@ -18,33 +174,209 @@ pub struct DeviceKey {
 
 impl DeviceKey {
 	pub fn get_active_layer(&self, tags: &TagList) -> &DeviceKeyLayer {
-		match self.layers.iter().find(|layer| layer.is_match(tags)) {
+		self.find_active_layer(|_, layer| layer.is_match(tags))
+	}
+
+	// the highest priority match wins, ties go to the earlier layer
+	pub fn find_active_layer<F>(&self, is_match: F) -> &DeviceKeyLayer
+	where
+		F: Fn(usize, &TaggedDeviceKeyLayer) -> bool,
+	{
+		self.layer(self.find_active_layer_index(is_match))
+	}
+
+	// like find_active_layer, as an index into all_layers
+	pub fn find_active_layer_index<F>(&self, is_match: F) -> usize
+	where
+		F: Fn(usize, &TaggedDeviceKeyLayer) -> bool,
+	{
+		let mut active: Option<(usize, &TaggedDeviceKeyLayer)> = None;
+
+		for (index, layer) in self.layers.iter().enumerate() {
+			if !is_match(index, layer) || layer.falls_through() {
+				continue;
+			}
+
+			if active.is_none_or(|(_, active)| layer.priority > active.priority) {
+				active = Some((index, layer));
+			}
+		}
+
+		match active {
+			Some((index, _)) => index + 1,
+			None => 0,
+		}
+	}
+
+	// by index into all_layers, the default layer if there is no such layer
+	pub fn layer(&self, index: usize) -> &DeviceKeyLayer {
+		match index
+			.checked_sub(1)
+			.and_then(|index| self.layers.get(index))
+		{
 			Some(layer) => &layer.layer,
 			None => &self.default_layer,
 		}
 	}
+
+	pub fn all_layers(&self) -> impl Iterator<Item = &DeviceKeyLayer> {
+		core::iter::once(&self.default_layer).chain(self.layers.iter().map(|layer| &layer.layer))
+	}
 }
 
 pub struct TaggedDeviceKeyLayer {
 	pub layer: DeviceKeyLayer,
 	pub tags: Vec<LayerTag>,
 	pub match_type: TagMatchType,
+	pub priority: i32,
+	pub mode: LayerMode,
 }
 
 impl TaggedDeviceKeyLayer {
+	pub fn compile_condition(&self, tags: &mut TagList) -> TagCondition {
+		match &self.match_type {
+			TagMatchType::All => match self.compile_tags(tags) {
+				(set, true) => TagCondition::All(set),
+				(_, false) => TagCondition::Any(TagSet::default()),
+			},
+			TagMatchType::Any => TagCondition::Any(self.compile_tags(tags).0),
+			TagMatchType::Expression(expr) => expr.compile(tags),
+		}
+	}
+
+	// also tells whether every tag fit in the tag list. Conditions can't see the ones that didn't,
+	// so an All layer can't match and an Any layer only needs the others
+	fn compile_tags(&self, tags: &mut TagList) -> (TagSet, bool) {
+		let mut set = TagSet::default();
+		let mut complete = true;
+		for tag in self.tags.iter() {
+			match tags.intern(tag) {
+				Some(id) => set.insert(id),
+				None => complete = false,
+			}
+		}
+		(set, complete)
+	}
+
+	fn falls_through(&self) -> bool {
+		match self.mode {
+			LayerMode::Opaque => false,
+			LayerMode::Transparent => self.layer.is_empty(),
+		}
+	}
+
 	fn is_match(&self, tags: &TagList) -> bool {
-		match self.match_type {
+		match &self.match_type {
 			TagMatchType::All => tags.contains_all(&self.tags),
 			TagMatchType::Any => tags.contains_any(&self.tags),
+			TagMatchType::Expression(expr) => expr.evaluate(tags),
 		}
 	}
 }
 
 pub struct DeviceKeyLayer {
 	pub id: LayerId,
-	pub macros: Vec<Macro>,
+	pub macros: Vec<MacroRef>,
+	pub press_macros: Vec<PressDurationMacro>,
+}
+
+impl DeviceKeyLayer {
+	pub fn is_empty(&self) -> bool {
+		self.macros.is_empty() && self.press_macros.is_empty()
+	}
+
+	pub fn all_macros(&self) -> impl Iterator<Item = &MacroRef> {
+		self.macros
+			.iter()
+			.chain(self.press_macros.iter().map(|pm| &pm.macro_))
+	}
+}
+
+pub struct PressDurationMacro {
+	pub duration: PressDuration,
+	pub macro_: MacroRef,
+}
+
+pub enum PressDuration {
+	// played on release if the key was held for less than the given time
+	ReleasedWithin(u32),
+	// played as soon as the key has been held for the given time
+	HeldFor(u32),
+}
+
+pub enum MacroRef {
+	Inline(Macro),
+	// refers to a macro in the profile library
+	Library(MacroId),
+}
+
+impl MacroRef {
+	pub fn resolve<'a>(&'a self, profile: &'a KeyboardProfile) -> Option<&'a Macro> {
+		match self {
+			MacroRef::Inline(macro_) => Some(macro_),
+			MacroRef::Library(id) => profile.find_library_macro(*id),
+		}
+	}
+}
+
+// where a macro is defined in a profile, so a state can find it again without borrowing it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacroLoc {
+	Library(usize),
+	// key, index into DeviceKey::all_layers and index into DeviceKeyLayer::all_macros
+	Key(usize, usize, usize),
+	Trigger(usize),
+	Idle(usize),
+	Startup,
+}
+
+impl MacroLoc {
+	// an inline macro is found at inline, a library reference in the library
+	pub fn of<'a>(
+		macro_ref: &'a MacroRef,
+		inline: MacroLoc,
+		profile: &'a KeyboardProfile,
+	) -> Option<(MacroLoc, &'a Macro)> {
+		match macro_ref {
+			MacroRef::Inline(macro_) => Some((inline, macro_)),
+			MacroRef::Library(id) => MacroLoc::library(*id, profile),
+		}
+	}
+
+	pub fn library(id: MacroId, profile: &KeyboardProfile) -> Option<(MacroLoc, &Macro)> {
+		let index = profile.library.iter().position(|macro_| macro_.id == id)?;
+		Some((MacroLoc::Library(index), &profile.library[index]))
+	}
+
+	pub fn resolve(self, profile: &KeyboardProfile) -> Option<&Macro> {
+		let macro_ref = match self {
+			MacroLoc::Library(index) => return profile.library.get(index),
+			MacroLoc::Key(key, layer, index) => profile
+				.keys
+				.get(key)?
+				.all_layers()
+				.nth(layer)?
+				.all_macros()
+				.nth(index)?,
+			MacroLoc::Trigger(index) => &profile.tag_triggers.get(index)?.macro_,
+			MacroLoc::Idle(index) => profile.idle.get(index)?.macro_.as_ref()?,
+			MacroLoc::Startup => profile.startup_macro.as_ref()?,
+		};
+
+		match macro_ref {
+			MacroRef::Inline(macro_) => Some(macro_),
+			MacroRef::Library(_) => None,
+		}
+	}
+}
+
+impl From<Macro> for MacroRef {
+	fn from(macro_: Macro) -> Self {
+		MacroRef::Inline(macro_)
+	}
 }
 
+#[derive(PartialEq)]
 pub struct Macro {
 	pub id: MacroId,
 	pub name: String,
@@ -53,27 +385,93 @@ pub struct Macro {
 	pub start_sequence: Sequence,
 	pub loop_sequence: Sequence,
 	pub end_sequence: Sequence,
+	pub max_loops: Option<u32>,
+	pub max_loop_ms: Option<u32>,
+	pub trigger_mode: TriggerMode,
+	pub speed_multiplier: Option<f32>,
+	pub cut_mode: StopMode,
 }
 
+impl Macro {
+	pub fn called_macros(&self) -> impl Iterator<Item = MacroId> + '_ {
+		[
+			&self.start_sequence,
+			&self.loop_sequence,
+			&self.end_sequence,
+		]
+		.into_iter()
+		.flat_map(|sequence| sequence.actions.iter())
+		.filter_map(|action| match action.action_event {
+			ActionEvent::Call(id, _) => Some(id),
+			_ => None,
+		})
+	}
+}
+
+#[derive(PartialEq)]
 pub struct Sequence {
 	pub actions: Vec<Action>,
 }
 
+#[derive(PartialEq)]
 pub struct Action {
 	pub predelay_ms: u32,
+	// the predelay varies randomly by up to this much in either direction
+	pub jitter_ms: Option<u32>,
 	pub action_event: ActionEvent,
 }
 
+#[derive(Clone, PartialEq)]
 pub enum ActionEvent {
 	None,
 	Keyboard(KeyboardEvent),
 	Mouse(MouseEvent),
 	Layer(LayerEvent),
+	Call(MacroId, CallMode),
+	StopAll(StopMode),
+	Pause(MacroId),
+	Resume(MacroId),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CallMode {
+	// the calling sequence waits for the called macro to finish
+	Blocking,
+	// the called macro runs alongside the caller
+	Detached,
+}
+
+#[derive(PartialEq)]
+pub enum TriggerMode {
+	// starts on press, stops on release
+	Hold,
+	// starts on the first press, stops on the next press
+	Toggle,
+	// plays start and end sequences once the key is released
+	OnRelease,
+	// plays start, a single loop pass and end regardless of release
+	OneShot,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StopMode {
+	// finish the current loop pass and play the end sequence
+	Graceful,
+	// finish right away, releasing anything the macro still holds
+	Abort,
+}
+
+// a transparent layer without macros lets the next matching layer, or the default, through
+pub enum LayerMode {
+	Opaque,
+	Transparent,
 }
 
 pub enum TagMatchType {
 	All,
 	Any,
+	// the layer tags are ignored in favor of the expression
+	Expression(TagExpr),
 }
 
 #[derive(Debug, Clone, Copy, PartialEq)]
@@ -112,7 +510,7 @@ impl Channel {
 	}
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
 
 pub struct LayerTag(String);
 
@@ -120,19 +518,26 @@ impl LayerTag {
 	pub fn new(tag: String) -> Self {
 		LayerTag(tag)
 	}
+
+	pub fn as_str(&self) -> &str {
+		&self.0
+	}
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyboardEvent {
 	KeyDown(KeyboardKey),
 	KeyUp(KeyboardKey),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum KeyboardKey {
 	A,
 	B,
 	C,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MouseEvent {
 	ButtonDown(MouseButton),
 	ButtonUp(MouseButton),
@@ -143,6 +548,7 @@ pub enum MouseEvent {
 	Move(i32, i32),
 }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum MouseButton {
 	Left,
 	Right,
@@ -151,7 +557,329 @@ pub enum MouseButton {
 	Forward,
 }
 
+#[derive(Clone, PartialEq)]
 pub enum LayerEvent {
 	Clear(LayerTag),
 	Set(LayerTag),
+	Push(LayerTag, PushMode),
+	Pop,
+	// set for this many milliseconds, then cleared again
+	SetFor(LayerTag, u32),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PushMode {
+	// stacked tags below stay active
+	Overlay,
+	// stacked tags below are hidden until this one is popped
+	Exclusive,
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::MAX_TAGS;
+	use alloc::string::ToString;
+	use alloc::vec;
+
+	#[test]
+	fn profile_without_calls_is_valid() {
+		let profile = new_test_profile(vec![new_test_macro(MacroId::new(1), vec![])], vec![]);
+
+		assert_eq!(profile.validate(), Ok(()));
+	}
+
+	#[test]
+	fn profile_with_nested_calls_is_valid() {
+		let profile = new_test_profile(
+			vec![new_test_macro(MacroId::new(1), vec![MacroId::new(10)])],
+			vec![
+				new_test_macro(MacroId::new(10), vec![MacroId::new(11), MacroId::new(12)]),
+				new_test_macro(MacroId::new(11), vec![MacroId::new(12)]),
+				new_test_macro(MacroId::new(12), vec![]),
+			],
+		);
+
+		assert_eq!(profile.validate(), Ok(()));
+	}
+
+	#[test]
+	fn calling_unknown_macro_is_invalid() {
+		let profile = new_test_profile(
+			vec![new_test_macro(MacroId::new(1), vec![MacroId::new(10)])],
+			vec![],
+		);
+
+		assert_eq!(
+			profile.validate(),
+			Err(ProfileError::UnknownMacro(MacroId::new(10)))
+		);
+	}
+
+	#[test]
+	fn layer_referencing_library_macro_is_valid() {
+		let mut profile = new_test_profile(vec![], vec![new_test_macro(MacroId::new(10), vec![])]);
+		profile.keys[0].default_layer.macros = vec![MacroRef::Library(MacroId::new(10))];
+
+		assert_eq!(profile.validate(), Ok(()));
+	}
+
+	#[test]
+	fn layer_referencing_unknown_macro_is_invalid() {
+		let mut profile = new_test_profile(vec![], vec![new_test_macro(MacroId::new(10), vec![])]);
+		profile.keys[0].default_layer.press_macros = vec![PressDurationMacro {
+			duration: PressDuration::HeldFor(500),
+			macro_: MacroRef::Library(MacroId::new(11)),
+		}];
+
+		assert_eq!(
+			profile.validate(),
+			Err(ProfileError::UnknownMacro(MacroId::new(11)))
+		);
+	}
+
+	#[test]
+	fn macro_calling_itself_is_invalid() {
+		let profile = new_test_profile(
+			vec![],
+			vec![new_test_macro(MacroId::new(10), vec![MacroId::new(10)])],
+		);
+
+		assert_eq!(
+			profile.validate(),
+			Err(ProfileError::CallCycle(MacroId::new(10)))
+		);
+	}
+
+	#[test]
+	fn call_cycle_is_invalid() {
+		let profile = new_test_profile(
+			vec![new_test_macro(MacroId::new(1), vec![MacroId::new(10)])],
+			vec![
+				new_test_macro(MacroId::new(10), vec![MacroId::new(11)]),
+				new_test_macro(MacroId::new(11), vec![MacroId::new(12)]),
+				new_test_macro(MacroId::new(12), vec![MacroId::new(10)]),
+			],
+		);
+
+		assert_eq!(
+			profile.validate(),
+			Err(ProfileError::CallCycle(MacroId::new(10)))
+		);
+	}
+
+	#[test]
+	fn unknown_tag_trigger_ref_is_invalid() {
+		let mut profile = new_test_profile(vec![], vec![]);
+		profile.tag_triggers.push(TagTrigger {
+			condition: TagExpr::parse("game").unwrap(),
+			edge: TriggerEdge::Enter,
+			macro_: MacroRef::Library(MacroId::new(10)),
+		});
+
+		assert_eq!(
+			profile.validate(),
+			Err(ProfileError::UnknownMacro(MacroId::new(10)))
+		);
+	}
+
+	#[test]
+	fn key_macros_are_located_inline_or_in_the_library() {
+		let mut profile = new_test_profile(
+			vec![new_test_macro(MacroId::new(1), vec![])],
+			vec![new_test_macro(MacroId::new(10), vec![])],
+		);
+		profile.keys[0].default_layer.press_macros = vec![PressDurationMacro {
+			duration: PressDuration::HeldFor(500),
+			macro_: MacroRef::Library(MacroId::new(10)),
+		}];
+		let find =
+			|id: i128| profile.find_key_macro(KeyId::new(1), LayerId::new(1), MacroId::new(id));
+
+		assert_eq!(find(1), Some(MacroLoc::Key(0, 0, 0)));
+		assert_eq!(find(10), Some(MacroLoc::Library(0)));
+		assert_eq!(find(2), None);
+		assert_eq!(
+			MacroLoc::Key(0, 0, 0).resolve(&profile).map(|m| m.id),
+			Some(MacroId::new(1))
+		);
+		// the location of a library reference holds no macro of its own
+		assert!(MacroLoc::Key(0, 0, 1).resolve(&profile).is_none());
+	}
+
+	// ------- LAYER TESTS --------
+
+	#[test]
+	fn higher_priority_layer_wins() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 0, LayerMode::Opaque, true),
+			new_test_layer(3, 5, LayerMode::Opaque, true),
+		]);
+
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(3));
+	}
+
+	#[test]
+	fn equal_priority_keeps_layer_order() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 1, LayerMode::Opaque, true),
+			new_test_layer(3, 1, LayerMode::Opaque, true),
+		]);
+
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(2));
+	}
+
+	#[test]
+	fn empty_transparent_layer_falls_through() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 0, LayerMode::Opaque, true),
+			new_test_layer(3, 5, LayerMode::Transparent, false),
+		]);
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(2));
+
+		let key = new_test_layered_key(vec![new_test_layer(3, 5, LayerMode::Transparent, false)]);
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(1));
+	}
+
+	#[test]
+	fn transparent_layer_with_macros_is_used() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 0, LayerMode::Opaque, true),
+			new_test_layer(3, 5, LayerMode::Transparent, true),
+		]);
+
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(3));
+	}
+
+	#[test]
+	fn empty_opaque_layer_blocks_lower_layers() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 0, LayerMode::Opaque, true),
+			new_test_layer(3, 5, LayerMode::Opaque, false),
+		]);
+
+		assert_eq!(key.get_active_layer(&new_test_tags()).id, LayerId::new(3));
+	}
+
+	#[test]
+	fn active_layer_index_counts_the_default_layer_first() {
+		let key = new_test_layered_key(vec![
+			new_test_layer(2, 0, LayerMode::Opaque, true),
+			new_test_layer(3, 5, LayerMode::Opaque, true),
+		]);
+
+		assert_eq!(key.find_active_layer_index(|index, _| index == 0), 1);
+		assert_eq!(key.find_active_layer_index(|_, _| true), 2);
+		assert_eq!(key.find_active_layer_index(|_, _| false), 0);
+		assert_eq!(key.layer(2).id, LayerId::new(3));
+		assert_eq!(key.layer(3).id, LayerId::new(1));
+	}
+
+	#[test]
+	fn conditions_with_tags_past_max_tags() {
+		let mut tags = new_test_tags();
+		// leaves room for the test tag only
+		for index in 0..MAX_TAGS - 1 {
+			tags.intern(&LayerTag::new(index.to_string()));
+		}
+		let mut layer = new_test_layer(2, 0, LayerMode::Opaque, true);
+		layer.tags.push(LayerTag::new("extra".to_string()));
+
+		assert!(!layer.compile_condition(&mut tags).evaluate(&tags));
+
+		layer.match_type = TagMatchType::Any;
+		assert!(layer.compile_condition(&mut tags).evaluate(&tags));
+	}
+
+	// ------- HELPERS --------
+
+	fn new_test_profile(key_macros: Vec<Macro>, library: Vec<Macro>) -> KeyboardProfile {
+		KeyboardProfile {
+			keys: vec![DeviceKey {
+				key_id: KeyId::new(1),
+				layers: Vec::new(),
+				default_layer: DeviceKeyLayer {
+					id: LayerId::new(1),
+					macros: key_macros.into_iter().map(MacroRef::from).collect(),
+					press_macros: Vec::new(),
+				},
+			}],
+			library,
+			channels: Vec::new(),
+			stop_all_clears_tags: false,
+			internal_tag_mode: InternalTagMode::Counted,
+			tag_triggers: Vec::new(),
+			startup_macro: None,
+			idle: Vec::new(),
+		}
+	}
+
+	fn new_test_macro(id: MacroId, calls: Vec<MacroId>) -> Macro {
+		Macro {
+			id,
+			name: "Name".to_string(),
+			play_channel: None,
+			cut_channels: vec![],
+			start_sequence: Sequence {
+				actions: calls
+					.into_iter()
+					.map(|callee| Action {
+						predelay_ms: 0,
+						jitter_ms: None,
+						action_event: ActionEvent::Call(callee, CallMode::Blocking),
+					})
+					.collect(),
+			},
+			loop_sequence: Sequence { actions: vec![] },
+			end_sequence: Sequence { actions: vec![] },
+			max_loops: None,
+			max_loop_ms: None,
+			trigger_mode: TriggerMode::Hold,
+			speed_multiplier: None,
+			cut_mode: StopMode::Graceful,
+		}
+	}
+
+	fn new_test_layered_key(layers: Vec<TaggedDeviceKeyLayer>) -> DeviceKey {
+		DeviceKey {
+			key_id: KeyId::new(1),
+			layers,
+			default_layer: DeviceKeyLayer {
+				id: LayerId::new(1),
+				macros: vec![new_test_macro(MacroId::new(1), vec![]).into()],
+				press_macros: Vec::new(),
+			},
+		}
+	}
+
+	// a layer active on the "test" tag
+	fn new_test_layer(
+		id: i128,
+		priority: i32,
+		mode: LayerMode,
+		with_macro: bool,
+	) -> TaggedDeviceKeyLayer {
+		let macros = match with_macro {
+			true => vec![new_test_macro(MacroId::new(id), vec![]).into()],
+			false => Vec::new(),
+		};
+
+		TaggedDeviceKeyLayer {
+			layer: DeviceKeyLayer {
+				id: LayerId::new(id),
+				macros,
+				press_macros: Vec::new(),
+			},
+			tags: vec![LayerTag::new("test".to_string())],
+			match_type: TagMatchType::All,
+			priority,
+			mode,
+		}
+	}
+
+	fn new_test_tags() -> TagList {
+		let mut tags = TagList::new();
+		tags.add_internal(LayerTag::new("test".to_string()));
+		tags
+	}
 }
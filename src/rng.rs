@@ -0,0 +1,70 @@
+// xorshift32, small and deterministic enough for timing jitter
+pub struct Rng {
+	state: u32,
+}
+
+impl Rng {
+	pub fn new(seed: u32) -> Self {
+		Rng {
+			// xorshift gets stuck on zero
+			state: if seed == 0 { 0x9E37_79B9 } else { seed },
+		}
+	}
+
+	pub fn next_u32(&mut self) -> u32 {
+		let mut x = self.state;
+		x ^= x << 13;
+		x ^= x >> 17;
+		x ^= x << 5;
+		self.state = x;
+		x
+	}
+
+	// uniformly distributed in [-range, range]
+	pub fn next_offset(&mut self, range: u32) -> i64 {
+		let span = 2 * range as u64 + 1;
+		(self.next_u32() as u64 % span) as i64 - range as i64
+	}
+
+	pub fn fork(&mut self) -> Rng {
+		Rng::new(self.next_u32())
+	}
+}
+
+impl Default for Rng {
+	fn default() -> Self {
+		Rng::new(0)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn same_seed_gives_same_sequence() {
+		let mut a = Rng::new(42);
+		let mut b = Rng::new(42);
+
+		for _ in 0..100 {
+			assert_eq!(a.next_u32(), b.next_u32());
+		}
+	}
+
+	#[test]
+	fn offsets_stay_in_range() {
+		let mut rng = Rng::new(7);
+
+		for _ in 0..1000 {
+			let offset = rng.next_offset(10);
+			assert!((-10..=10).contains(&offset));
+		}
+	}
+
+	#[test]
+	fn zero_range_gives_zero_offset() {
+		let mut rng = Rng::new(7);
+
+		assert_eq!(rng.next_offset(0), 0);
+	}
+}
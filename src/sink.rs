@@ -0,0 +1,326 @@
+extern crate alloc;
+
+use alloc::vec::Vec;
+
+use crate::profile::*;
+
+// the part of an action that leaves the keyboard, copied out of the profile so sinks don't
+// borrow it. Layer and control events stay inside KeyboardState
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum OutputEvent {
+	Keyboard(KeyboardEvent),
+	Mouse(MouseEvent),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TimedOutput {
+	pub event: OutputEvent,
+	// from the start of the tick
+	pub offset_ms: u32,
+	pub macro_id: Option<MacroId>,
+	pub key_id: Option<KeyId>,
+}
+
+// where KeyboardState::tick writes its events. Fixed capacity sinks drop events that don't fit,
+// so size them for the busiest tick
+pub trait EventSink {
+	fn push(&mut self, event: TimedOutput);
+
+	// called after the events of a tick, elapsed_ms is how long the tick was
+	fn end_tick(&mut self, _elapsed_ms: u32) {}
+}
+
+impl EventSink for Vec<OutputEvent> {
+	fn push(&mut self, event: TimedOutput) {
+		Vec::push(self, event.event);
+	}
+}
+
+impl<const N: usize> EventSink for heapless::Deque<TimedOutput, N> {
+	fn push(&mut self, event: TimedOutput) {
+		let _ = self.push_back(event);
+	}
+}
+
+impl<const N: usize> EventSink for heapless::spsc::Queue<TimedOutput, N> {
+	fn push(&mut self, event: TimedOutput) {
+		let _ = self.enqueue(event);
+	}
+}
+
+// records every event with the time it happened at, counted from the first tick
+#[derive(Default)]
+pub struct TraceSink {
+	pub records: Vec<TraceRecord>,
+	elapsed_ms: u64,
+}
+
+pub struct TraceRecord {
+	pub at_ms: u64,
+	pub event: TimedOutput,
+}
+
+impl TraceSink {
+	pub fn new() -> Self {
+		TraceSink::default()
+	}
+
+	pub fn events(&self) -> impl Iterator<Item = OutputEvent> + '_ {
+		self.records.iter().map(|record| record.event.event)
+	}
+}
+
+impl EventSink for TraceSink {
+	fn push(&mut self, event: TimedOutput) {
+		self.records.push(TraceRecord {
+			at_ms: self.elapsed_ms + event.offset_ms as u64,
+			event,
+		});
+	}
+
+	fn end_tick(&mut self, elapsed_ms: u32) {
+		self.elapsed_ms += elapsed_ms as u64;
+	}
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct KeyboardReport {
+	pub modifiers: u8,
+	pub keys: [u8; 6],
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct MouseReport {
+	pub buttons: u8,
+	pub x: i8,
+	pub y: i8,
+	pub wheel: i8,
+	pub pan: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum HidReport {
+	Keyboard(KeyboardReport),
+	Mouse(MouseReport),
+}
+
+// turns events into boot protocol style reports and hands each one to `write` together with
+// its offset into the tick
+pub struct HidReporter<F>
+where
+	F: FnMut(u32, HidReport),
+{
+	keyboard: KeyboardReport,
+	buttons: u8,
+	write: F,
+}
+
+impl<F> HidReporter<F>
+where
+	F: FnMut(u32, HidReport),
+{
+	pub fn new(write: F) -> Self {
+		HidReporter {
+			keyboard: KeyboardReport::default(),
+			buttons: 0,
+			write,
+		}
+	}
+
+	fn key_down(&mut self, usage: u8) {
+		if self.keyboard.keys.contains(&usage) {
+			return;
+		}
+		// past six keys the press is dropped
+		if let Some(slot) = self.keyboard.keys.iter_mut().find(|slot| **slot == 0) {
+			*slot = usage;
+		}
+	}
+
+	fn key_up(&mut self, usage: u8) {
+		if let Some(index) = self.keyboard.keys.iter().position(|slot| *slot == usage) {
+			self.keyboard.keys.copy_within(index + 1.., index);
+			self.keyboard.keys[5] = 0;
+		}
+	}
+
+	fn mouse(&self) -> MouseReport {
+		MouseReport {
+			buttons: self.buttons,
+			..MouseReport::default()
+		}
+	}
+}
+
+impl<F> EventSink for HidReporter<F>
+where
+	F: FnMut(u32, HidReport),
+{
+	fn push(&mut self, event: TimedOutput) {
+		let report = match event.event {
+			OutputEvent::Keyboard(KeyboardEvent::KeyDown(key)) => {
+				self.key_down(key_usage(key));
+				HidReport::Keyboard(self.keyboard)
+			}
+			OutputEvent::Keyboard(KeyboardEvent::KeyUp(key)) => {
+				self.key_up(key_usage(key));
+				HidReport::Keyboard(self.keyboard)
+			}
+			OutputEvent::Mouse(MouseEvent::ButtonDown(button)) => {
+				self.buttons |= button_mask(button);
+				HidReport::Mouse(self.mouse())
+			}
+			OutputEvent::Mouse(MouseEvent::ButtonUp(button)) => {
+				self.buttons &= !button_mask(button);
+				HidReport::Mouse(self.mouse())
+			}
+			OutputEvent::Mouse(MouseEvent::Move(x, y)) => HidReport::Mouse(MouseReport {
+				x: clamp_delta(x),
+				y: clamp_delta(y),
+				..self.mouse()
+			}),
+			OutputEvent::Mouse(MouseEvent::ScrollUp(amount)) => HidReport::Mouse(MouseReport {
+				wheel: clamp_delta(amount),
+				..self.mouse()
+			}),
+			OutputEvent::Mouse(MouseEvent::ScrollDown(amount)) => HidReport::Mouse(MouseReport {
+				wheel: clamp_delta(-amount),
+				..self.mouse()
+			}),
+			OutputEvent::Mouse(MouseEvent::ScrollRight(amount)) => HidReport::Mouse(MouseReport {
+				pan: clamp_delta(amount),
+				..self.mouse()
+			}),
+			OutputEvent::Mouse(MouseEvent::ScrollLeft(amount)) => HidReport::Mouse(MouseReport {
+				pan: clamp_delta(-amount),
+				..self.mouse()
+			}),
+		};
+
+		(self.write)(event.offset_ms, report);
+	}
+}
+
+fn key_usage(key: KeyboardKey) -> u8 {
+	match key {
+		KeyboardKey::A => 0x04,
+		KeyboardKey::B => 0x05,
+		KeyboardKey::C => 0x06,
+	}
+}
+
+fn button_mask(button: MouseButton) -> u8 {
+	match button {
+		MouseButton::Left => 0x01,
+		MouseButton::Right => 0x02,
+		MouseButton::Middle => 0x04,
+		MouseButton::Back => 0x08,
+		MouseButton::Forward => 0x10,
+	}
+}
+
+fn clamp_delta(delta: i32) -> i8 {
+	delta.clamp(i8::MIN as i32, i8::MAX as i32) as i8
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+
+	const KEY_A_DOWN: OutputEvent = OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A));
+	const KEY_B_DOWN: OutputEvent = OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B));
+	const KEY_A_UP: OutputEvent = OutputEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A));
+	const LEFT_DOWN: OutputEvent = OutputEvent::Mouse(MouseEvent::ButtonDown(MouseButton::Left));
+	const MOVE: OutputEvent = OutputEvent::Mouse(MouseEvent::Move(300, -2));
+
+	#[test]
+	fn vec_sink_collects_events() {
+		let mut sink: Vec<OutputEvent> = Vec::new();
+		EventSink::push(&mut sink, timed(KEY_A_DOWN, 0));
+		EventSink::push(&mut sink, timed(KEY_A_UP, 5));
+
+		assert_eq!(sink, vec![KEY_A_DOWN, KEY_A_UP]);
+	}
+
+	#[test]
+	fn heapless_sinks_drop_events_past_capacity() {
+		let mut deque: heapless::Deque<TimedOutput, 2> = heapless::Deque::new();
+		let mut queue: heapless::spsc::Queue<TimedOutput, 3> = heapless::spsc::Queue::new();
+		for offset_ms in 0..3 {
+			deque.push(timed(MOVE, offset_ms));
+			queue.push(timed(MOVE, offset_ms));
+		}
+
+		assert_eq!(deque.len(), 2);
+		assert_eq!(deque.back().unwrap().offset_ms, 1);
+		// a queue of N holds N - 1
+		assert_eq!(queue.len(), 2);
+	}
+
+	#[test]
+	fn trace_sink_records_absolute_time() {
+		let mut sink = TraceSink::new();
+		sink.push(timed(KEY_A_DOWN, 5));
+		sink.end_tick(10);
+		sink.push(timed(KEY_A_UP, 3));
+
+		assert_eq!(
+			sink.records.iter().map(|r| r.at_ms).collect::<Vec<_>>(),
+			vec![5, 13]
+		);
+		assert_eq!(sink.events().count(), 2);
+	}
+
+	#[test]
+	fn hid_reporter_tracks_held_keys() {
+		let mut reports = Vec::new();
+		let mut reporter = HidReporter::new(|offset_ms, report| reports.push((offset_ms, report)));
+		reporter.push(timed(KEY_A_DOWN, 0));
+		reporter.push(timed(KEY_B_DOWN, 4));
+		reporter.push(timed(KEY_A_UP, 6));
+
+		assert_eq!(
+			reports,
+			vec![
+				(0, keyboard_report([0x04, 0, 0, 0, 0, 0])),
+				(4, keyboard_report([0x04, 0x05, 0, 0, 0, 0])),
+				(6, keyboard_report([0x05, 0, 0, 0, 0, 0])),
+			]
+		);
+	}
+
+	#[test]
+	fn hid_reporter_keeps_buttons_across_moves() {
+		let mut reports = Vec::new();
+		let mut reporter = HidReporter::new(|_, report| reports.push(report));
+		reporter.push(timed(LEFT_DOWN, 0));
+		reporter.push(timed(MOVE, 0));
+
+		assert_eq!(
+			reports[1],
+			HidReport::Mouse(MouseReport {
+				buttons: 0x01,
+				x: 127,
+				y: -2,
+				wheel: 0,
+				pan: 0,
+			})
+		);
+	}
+
+	// ------- HELPERS --------
+
+	fn timed(event: OutputEvent, offset_ms: u32) -> TimedOutput {
+		TimedOutput {
+			event,
+			offset_ms,
+			macro_id: None,
+			key_id: None,
+		}
+	}
+
+	fn keyboard_report(keys: [u8; 6]) -> HidReport {
+		HidReport::Keyboard(KeyboardReport { modifiers: 0, keys })
+	}
+}
@@ -1,71 +1,518 @@
 extern crate alloc;
 extern crate serde_json_core;
 
+use core::borrow::Borrow;
 use core::fmt;
 
 use crate::profile::*;
-use crate::TagList;
+use crate::rng::Rng;
+use crate::sink::{EventSink, OutputEvent, TimedOutput};
+use crate::{TagCondition, TagList, TagSet};
+use alloc::boxed::Box;
+use alloc::vec;
 use alloc::vec::Vec;
 
-pub struct KeyboardState<'a> {
-	keys: Vec<KeyState<'a>>,
+const MAX_CALL_DEPTH: u8 = 8;
+// how many tag triggers can set each other off in a row
+const MAX_TRIGGER_CHAIN: u8 = 8;
+
+fn changes_tags(events: &[TimedEvent]) -> bool {
+	events
+		.iter()
+		.any(|timed| matches!(timed.event, ActionEvent::Layer(_)))
+}
+
+pub struct KeyboardState<P> {
+	profiles: Profiles<P>,
+	// one per key of the profile, in the same order
+	keys: Vec<KeyState>,
 	tags: TagList,
-	macros: Vec<MacroState<'a>>,
+	// tags as of the last layer update, used to report changes
+	active_tags: TagSet,
+	active_others: Vec<LayerTag>,
+	notifications: NotificationQueue,
+	tag_triggers: Vec<TagTriggerState>,
+	startup_pending: bool,
+	idle_ms: u32,
+	// one per idle action of the profile
+	idle_fired: Vec<bool>,
+	macros: Vec<MacroState>,
+	pending_presses: Vec<PendingPress>,
+	timed_tags: Vec<TimedTag>,
+	// last timestamp given to the *_at functions and the sub millisecond time left over from it
+	clock_us: Option<u64>,
+	carry_us: u64,
+	rng: Rng,
+	// everything the emitted events have pressed and not released
+	held: HeldInput,
+	// releases from aborted macros, emitted on the next tick
+	releases: Vec<TimedEvent>,
+	// reused by tick to collect timed events before they go to the sink
+	scratch: Vec<TimedEvent>,
+	// chain of the macros whose events are changing tags right now, 0 outside of tick
+	trigger_chain: u8,
 }
 
-impl<'a> KeyboardState<'a> {
-	pub fn from(profile: &'a KeyboardProfile) -> Self {
+// the profile is borrowed, or owned as in OwnedKeyboardState
+impl<P: Borrow<KeyboardProfile>> KeyboardState<P> {
+	pub fn from(profile: P) -> Self {
+		let mut tags = TagList::new();
+		let (keys, tag_triggers, idle_fired) = {
+			let profile = profile.borrow();
+			tags.set_internal_mode(profile.internal_tag_mode);
+			(
+				Self::map_keys_from_profile(profile, &mut tags),
+				Self::map_triggers_from_profile(profile, &mut tags),
+				vec![false; profile.idle.len()],
+			)
+		};
+
 		KeyboardState {
-			keys: KeyboardState::map_keys_from_profile(profile),
-			tags: TagList::new(),
+			profiles: Profiles {
+				current: profile,
+				generation: 0,
+				retired: Vec::new(),
+			},
+			keys,
+			tag_triggers,
+			tags,
+			startup_pending: true,
+			idle_ms: 0,
+			idle_fired,
+			active_tags: TagSet::default(),
+			active_others: Vec::new(),
+			notifications: NotificationQueue::default(),
 			macros: Vec::new(),
+			pending_presses: Vec::new(),
+			timed_tags: Vec::new(),
+			clock_us: None,
+			carry_us: 0,
+			rng: Rng::default(),
+			held: HeldInput::default(),
+			releases: Vec::new(),
+			scratch: Vec::new(),
+			trigger_chain: 0,
 		}
 	}
 
-	pub fn update_key_profile(&mut self, profile: &'a KeyboardProfile) {
-		self.keys = KeyboardState::map_keys_from_profile(profile);
+	pub fn profile(&self) -> &KeyboardProfile {
+		self.profiles.current()
+	}
+
+	pub fn seed_rng(&mut self, seed: u32) {
+		self.rng = Rng::new(seed);
+	}
+
+	pub fn update_key_profile(&mut self, profile: P) {
+		self.update_key_profile_with(profile, ProfileUpdate::StopMacros);
+	}
+
+	// macros that don't carry on in the new profile finish gracefully from the old one, which is
+	// kept until the last of them is done
+	pub fn update_key_profile_with(&mut self, profile: P, update: ProfileUpdate) {
+		// idle tags belong to the old profile
+		self.wake_from_idle();
+		self.startup_pending = true;
+		self.pending_presses.clear();
+		let previous: Vec<(KeyId, LayerId)> = self
+			.profiles
+			.current()
+			.keys
+			.iter()
+			.zip(self.keys.iter())
+			.map(|(key, ks)| (key.key_id, ks.layer_id))
+			.collect();
+		self.profiles.replace(profile);
+
+		let profile = self.profiles.current();
+		self.idle_fired = vec![false; profile.idle.len()];
+		self.keys = Self::map_keys_from_profile(profile, &mut self.tags);
+		for (ks, key) in self.keys.iter_mut().zip(profile.keys.iter()) {
+			if let Some((_, layer_id)) = previous.iter().find(|(id, _)| *id == key.key_id) {
+				ks.layer_id = *layer_id;
+			}
+		}
+		self.tag_triggers = Self::map_triggers_from_profile(profile, &mut self.tags);
+		self.tags.set_internal_mode(profile.internal_tag_mode);
 
-		// release all
+		let profiles = &self.profiles;
 		for macro_ in self.macros.iter_mut() {
-			macro_.stop();
+			let unchanged = match (update, macro_.source) {
+				(ProfileUpdate::KeepUnchangedMacros, MacroSource::Key { key, layer }) => profile
+					.find_key_macro(key, layer, macro_.id)
+					.filter(|&loc| {
+						macro_.is_unchanged_in(profiles.get(macro_.generation), profile, loc)
+					}),
+				_ => None,
+			};
+
+			match unchanged {
+				Some(loc) => macro_.rebind(profile, loc, profiles.generation),
+				None => macro_.stop(),
+			}
 		}
 
+		self.drop_retired_profiles();
 		self.update_layers();
 	}
 
 	pub fn press_key(&mut self, key_id: KeyId) {
-		if let Some(key) = self.keys.iter().find(|ks| ks.key.key_id == key_id) {
-			let macros: Vec<MacroState<'a>> = key
-				.current_layer
+		// wake first so the press resolves against the layers it would have had before idling
+		if self.wake_from_idle() {
+			self.update_layers();
+		}
+
+		let profile = self.profiles.current();
+		let generation = self.profiles.generation;
+
+		if let Some(index) = profile.keys.iter().position(|key| key.key_id == key_id) {
+			let key = &self.keys[index];
+			let layer = profile.keys[index].layer(key.layer);
+			let source = MacroSource::Key {
+				key: key_id,
+				layer: layer.id,
+			};
+			let mut macros: Vec<MacroState> = Vec::new();
+
+			for (loc, macro_) in layer
 				.macros
 				.iter()
-				.map(|macro_| MacroState::from(macro_, key))
-				.collect();
+				.enumerate()
+				.filter_map(|(i, macro_ref)| {
+					MacroLoc::of(macro_ref, MacroLoc::Key(index, key.layer, i), profile)
+				}) {
+				match macro_.trigger_mode {
+					TriggerMode::Hold | TriggerMode::OneShot => {
+						macros.push(MacroState::new(
+							loc,
+							macro_,
+							source,
+							generation,
+							self.rng.fork(),
+						));
+					}
+					TriggerMode::Toggle => {
+						// a second press stops the running macro instead of starting another
+						let mut toggled_off = false;
+						for running in self.macros.iter_mut().filter(|m| {
+							m.source.key() == Some(key_id) && m.id == macro_.id && m.is_running()
+						}) {
+							running.stop();
+							toggled_off = true;
+						}
 
-			self.cut_channels(
-				macros
-					.iter()
-					.flat_map(|m| m.macro_.cut_channels.clone())
-					.collect(),
-			);
-			self.macros.extend(macros);
+						if !toggled_off {
+							macros.push(MacroState::new(
+								loc,
+								macro_,
+								source,
+								generation,
+								self.rng.fork(),
+							));
+						}
+					}
+					TriggerMode::OnRelease => {}
+				}
+			}
+
+			// press duration macros are picked once the key is released or held long enough
+			self.pending_presses.retain(|p| p.key != key_id);
+			if !layer.press_macros.is_empty() {
+				self.pending_presses.push(PendingPress {
+					key: key_id,
+					held_ms: 0,
+					resolved: false,
+				});
+			}
+
+			self.start_macros(macros);
 		}
 	}
 
 	pub fn release_key(&mut self, key_id: KeyId) {
+		let profiles = &self.profiles;
 		for macro_ in self.macros.iter_mut() {
-			if macro_.source.key == key_id {
+			if macro_.source.key() == Some(key_id)
+				&& profiles
+					.macro_of(macro_)
+					.is_some_and(|m| matches!(m.trigger_mode, TriggerMode::Hold))
+			{
 				macro_.stop();
 			}
 		}
+
+		let profile = self.profiles.current();
+		let generation = self.profiles.generation;
+		let held_ms = self
+			.pending_presses
+			.iter()
+			.find(|p| p.key == key_id)
+			.map(|p| p.held_ms);
+		self.pending_presses.retain(|p| p.key != key_id);
+
+		if let Some(index) = profile.keys.iter().position(|key| key.key_id == key_id) {
+			let key = &self.keys[index];
+			let layer = profile.keys[index].layer(key.layer);
+			let source = MacroSource::Key {
+				key: key_id,
+				layer: layer.id,
+			};
+
+			let released_within = layer
+				.press_macros
+				.iter()
+				.enumerate()
+				.find(|(_, pm)| match (&pm.duration, held_ms) {
+					(PressDuration::ReleasedWithin(max_ms), Some(held_ms)) => held_ms < *max_ms,
+					_ => false,
+				})
+				.and_then(|(i, pm)| {
+					let inline = MacroLoc::Key(index, key.layer, layer.macros.len() + i);
+					MacroLoc::of(&pm.macro_, inline, profile)
+				});
+
+			let macros: Vec<MacroState> = layer
+				.macros
+				.iter()
+				.enumerate()
+				.filter_map(|(i, macro_ref)| {
+					MacroLoc::of(macro_ref, MacroLoc::Key(index, key.layer, i), profile)
+				})
+				.filter(|(_, macro_)| matches!(macro_.trigger_mode, TriggerMode::OnRelease))
+				.chain(released_within)
+				.map(|(loc, macro_)| {
+					// the key is already up, so the macro is started as released
+					let mut macro_state =
+						MacroState::new(loc, macro_, source, generation, self.rng.fork());
+					macro_state.stop();
+					macro_state
+				})
+				.collect();
+
+			self.start_macros(macros);
+		}
 	}
 
-	pub fn tick(&mut self, elapsed_ms: u32, events: &mut Vec<&'a ActionEvent>) {
-		for macro_ in self.macros.iter_mut() {
-			macro_.tick(elapsed_ms, events);
+	// timestamped alternatives to press_key, release_key and tick, driven by a monotonic
+	// microsecond clock. Time is advanced deadline by deadline so everything happens at its
+	// scheduled time rather than when the host gets around to ticking. Don't mix with tick.
+	pub fn press_key_at<S: EventSink>(&mut self, key_id: KeyId, now_us: u64, events: &mut S) {
+		self.tick_at(now_us, events);
+		self.press_key(key_id);
+	}
+
+	pub fn release_key_at<S: EventSink>(&mut self, key_id: KeyId, now_us: u64, events: &mut S) {
+		self.tick_at(now_us, events);
+		self.release_key(key_id);
+	}
+
+	pub fn tick_at<S: EventSink>(&mut self, now_us: u64, events: &mut S) {
+		let last_us = *self.clock_us.get_or_insert(now_us);
+		let elapsed_us = now_us.saturating_sub(last_us) + self.carry_us;
+		self.clock_us = Some(now_us.max(last_us));
+		self.carry_us = elapsed_us % 1000;
+
+		let window_ms = (elapsed_us / 1000).min(u32::MAX as u64) as u32;
+		let mut timed = core::mem::take(&mut self.scratch);
+		let mut done_ms = 0;
+		loop {
+			let step_ms = match self.next_deadline_ms() {
+				Some(deadline_ms) if deadline_ms > 0 && deadline_ms < window_ms - done_ms => {
+					deadline_ms
+				}
+				_ => window_ms - done_ms,
+			};
+			let start = timed.len();
+			self.tick_timed(step_ms, &mut timed);
+			// the sink sees one tick for the whole window
+			shift_events(&mut timed[start..], done_ms);
+
+			done_ms += step_ms;
+			if done_ms == window_ms {
+				break;
+			}
+		}
+
+		Self::write_to_sink(&mut timed, window_ms, events);
+		self.scratch = timed;
+	}
+
+	pub fn tick<S: EventSink>(&mut self, elapsed_ms: u32, events: &mut S) {
+		let mut timed = core::mem::take(&mut self.scratch);
+		self.tick_timed(elapsed_ms, &mut timed);
+		Self::write_to_sink(&mut timed, elapsed_ms, events);
+		self.scratch = timed;
+	}
+
+	fn write_to_sink<S: EventSink>(timed: &mut Vec<TimedEvent>, elapsed_ms: u32, events: &mut S) {
+		for event in timed.drain(..).filter_map(TimedEvent::output) {
+			events.push(event);
+		}
+		events.end_tick(elapsed_ms);
+	}
+
+	// like tick, with each event's offset into the tick and the macro and key it came from
+	pub fn tick_timed(&mut self, elapsed_ms: u32, events: &mut Vec<TimedEvent>) {
+		let first = events.len();
+		self.emit_releases(events);
+		self.expire_timed_tags(elapsed_ms);
+		self.start_startup_macro();
+		self.update_idle(elapsed_ms);
+		let start = events.len();
+
+		let mut spawned: Vec<(MacroState, u32)> = Vec::new();
+		let mut chain = 0;
+
+		for macro_ in self.macros.iter_mut().filter(|m| !m.queued && !m.paused) {
+			let before = events.len();
+			Self::tick_macro(
+				macro_,
+				elapsed_ms,
+				elapsed_ms,
+				events,
+				self.profiles.get(macro_.generation),
+				&mut self.rng,
+				&mut spawned,
+			);
+			if changes_tags(&events[before..]) {
+				chain = chain.max(macro_.chain);
+			}
+		}
+
+		// detached macros start at the time they were called and may call others in turn
+		while let Some((macro_, remaining_ms)) = spawned.pop() {
+			let count = self.macros.len();
+			self.start_macros(vec![macro_]);
+
+			if self.macros.len() == count {
+				continue;
+			}
+			if let Some(macro_) = self.macros.last_mut().filter(|m| !m.queued) {
+				let before = events.len();
+				Self::tick_macro(
+					macro_,
+					elapsed_ms,
+					remaining_ms,
+					events,
+					self.profiles.get(macro_.generation),
+					&mut self.rng,
+					&mut spawned,
+				);
+				if changes_tags(&events[before..]) {
+					chain = chain.max(macro_.chain);
+				}
+			}
 		}
 
+		self.held.track(&events[first..]);
+
+		let mut stop_all = None;
+		let mut stop_all_ms = 0;
+		let mut tags_changed = false;
+		for timed in events[start..].iter() {
+			match &timed.event {
+				ActionEvent::Layer(LayerEvent::Set(tag)) => {
+					self.tags.add_internal(tag.clone());
+					tags_changed = true;
+				}
+				ActionEvent::Layer(LayerEvent::Clear(tag)) => {
+					self.tags.remove_internal(tag.clone());
+					tags_changed = true;
+				}
+				ActionEvent::Layer(LayerEvent::Push(tag, mode)) => {
+					self.tags.push(tag.clone(), *mode);
+					tags_changed = true;
+				}
+				ActionEvent::Layer(LayerEvent::Pop) => {
+					self.tags.pop();
+					tags_changed = true;
+				}
+				ActionEvent::Layer(LayerEvent::SetFor(tag, duration_ms)) => {
+					self.set_timed_tag(tag.clone(), *duration_ms);
+					tags_changed = true;
+				}
+				ActionEvent::StopAll(StopMode::Abort) => {
+					stop_all = Some(StopMode::Abort);
+					stop_all_ms = timed.offset_ms;
+				}
+				ActionEvent::StopAll(StopMode::Graceful) if stop_all.is_none() => {
+					stop_all = Some(StopMode::Graceful);
+					stop_all_ms = timed.offset_ms;
+				}
+				ActionEvent::Pause(id) => self.pause_macro(*id),
+				ActionEvent::Resume(id) => self.resume_macro(*id),
+				_ => {}
+			}
+		}
+		if tags_changed {
+			self.trigger_chain = chain;
+			self.update_layers();
+			self.trigger_chain = 0;
+		}
+		if let Some(mode) = stop_all {
+			self.stop_all(mode);
+			for release in self.releases.iter_mut() {
+				release.offset_ms = stop_all_ms;
+			}
+			self.emit_releases(events);
+		}
+		// macros play one after the other, but sinks build their output in push order
+		events[first..].sort_by_key(|timed| timed.offset_ms);
+
 		self.macros.retain(|macro_| !macro_.is_finished());
+		self.drop_retired_profiles();
+		self.start_queued_macros();
+		self.resolve_held_presses(elapsed_ms);
+	}
+
+	fn emit_releases(&mut self, events: &mut Vec<TimedEvent>) {
+		events.append(&mut self.releases);
+	}
+
+	pub fn stop_all(&mut self, mode: StopMode) {
+		self.pending_presses.clear();
+		// queued macros haven't played anything yet, so there is nothing to stop
+		self.macros.retain(|m| !m.queued);
+
+		for macro_ in self.macros.iter_mut() {
+			self.releases.extend(macro_.stop_with_mode(mode));
+		}
+
+		// macros that already finished can have left keys down as well
+		for release in self.held.release_all() {
+			if !self.releases.iter().any(|timed| timed.event == release) {
+				self.releases.push(TimedEvent {
+					event: release,
+					offset_ms: 0,
+					macro_id: None,
+					key_id: None,
+				});
+			}
+		}
+
+		if self.profile().stop_all_clears_tags {
+			self.tags.clear_internal();
+			self.timed_tags.clear();
+			self.update_layers();
+		}
+	}
+
+	// paused macros keep their progress and anything they hold until resumed. Stopping a
+	// macro resumes it so it can finish, and macros already stopping can't be paused
+	pub fn pause_macro(&mut self, id: MacroId) {
+		for macro_ in self
+			.macros
+			.iter_mut()
+			.filter(|m| m.id == id && m.is_running())
+		{
+			macro_.paused = true;
+		}
+	}
+
+	pub fn resume_macro(&mut self, id: MacroId) {
+		for macro_ in self.macros.iter_mut().filter(|m| m.id == id) {
+			macro_.paused = false;
+		}
 	}
 
 	pub fn add_internal_tags(&mut self, tags: Vec<LayerTag>) {
@@ -78,202 +525,1274 @@ impl<'a> KeyboardState<'a> {
 		self.update_layers();
 	}
 
+	// each timed set holds the tag on its own until it runs out, whatever the internal tag mode,
+	// and doesn't touch the count of the internal tag
+	pub fn add_internal_tag_for(&mut self, tag: LayerTag, duration_ms: u32) {
+		self.set_timed_tag(tag, duration_ms);
+		self.update_layers();
+	}
+
+	pub fn push_tag(&mut self, tag: LayerTag, mode: PushMode) {
+		self.tags.push(tag, mode);
+		self.update_layers();
+	}
+
+	pub fn pop_tag(&mut self) {
+		self.tags.pop();
+		self.update_layers();
+	}
+
 	pub fn set_external_tags(&mut self, tags: Vec<LayerTag>) {
 		self.tags.set_external(tags);
 		self.update_layers();
 	}
 
-	fn update_layers(&mut self) {
-		for ks in self.keys.iter_mut() {
-			let new_layer = ks.key.get_active_layer(&self.tags);
+	fn start_startup_macro(&mut self) {
+		if !self.startup_pending {
+			return;
+		}
+		self.startup_pending = false;
+
+		let profile = self.profiles.current();
+		if let Some((loc, macro_)) = profile
+			.startup_macro
+			.as_ref()
+			.and_then(|macro_ref| MacroLoc::of(macro_ref, MacroLoc::Startup, profile))
+		{
+			// nothing holds a startup macro, only one shot macros get to loop
+			let mut macro_state = MacroState::new(
+				loc,
+				macro_,
+				MacroSource::Startup,
+				self.profiles.generation,
+				self.rng.fork(),
+			);
+			if !matches!(macro_.trigger_mode, TriggerMode::OneShot) {
+				macro_state.stop();
+			}
+			self.start_macros(vec![macro_state]);
+		}
+	}
 
-			if ks.current_layer.id != new_layer.id {
-				// release macros that no longer have a valid source
-				for macro_ in self
-					.macros
-					.iter_mut()
-					.filter(|m| m.source.key == ks.key.key_id && m.source.layer != new_layer.id)
-				{
-					macro_.stop();
-				}
-				ks.current_layer = new_layer;
+	fn update_idle(&mut self, elapsed_ms: u32) {
+		let profile = self.profiles.current();
+		self.idle_ms = self.idle_ms.saturating_add(elapsed_ms);
+
+		let mut macros: Vec<MacroState> = Vec::new();
+		let mut tags_changed = false;
+		for (index, idle) in profile.idle.iter().enumerate() {
+			if self.idle_fired[index] || self.idle_ms < idle.timeout_ms {
+				continue;
+			}
+			self.idle_fired[index] = true;
+
+			for tag in idle.tags.iter() {
+				self.tags.add_internal(tag.clone());
+				tags_changed = true;
+			}
+			if let Some((loc, macro_)) = idle
+				.macro_
+				.as_ref()
+				.and_then(|macro_ref| MacroLoc::of(macro_ref, MacroLoc::Idle(index), profile))
+			{
+				macros.push(MacroState::new(
+					loc,
+					macro_,
+					MacroSource::Idle(index),
+					self.profiles.generation,
+					self.rng.fork(),
+				));
 			}
 		}
+
+		if tags_changed {
+			self.update_layers();
+		}
+		self.start_macros(macros);
 	}
 
-	fn cut_channels(&mut self, channels: Vec<Channel>) {
-		for macro_ in self
-			.macros
-			.iter_mut()
-			.filter(|m| match m.macro_.play_channel {
-				Some(channel) => channels.contains(&channel),
-				None => false,
-			}) {
-			macro_.stop();
+	// returns whether any idle tags were cleared
+	fn wake_from_idle(&mut self) -> bool {
+		self.idle_ms = 0;
+
+		let mut tags_changed = false;
+		for (index, idle) in self.profiles.current().idle.iter().enumerate() {
+			if !self.idle_fired[index] {
+				continue;
+			}
+			self.idle_fired[index] = false;
+
+			for tag in idle.tags.iter() {
+				self.tags.remove_internal(tag.clone());
+				tags_changed = true;
+			}
+			for macro_ in self
+				.macros
+				.iter_mut()
+				.filter(|m| m.source == MacroSource::Idle(index))
+			{
+				macro_.stop();
+			}
 		}
+
+		tags_changed
 	}
 
-	fn map_keys_from_profile(profile: &'a KeyboardProfile) -> Vec<KeyState<'a>> {
-		profile.keys.iter().map(KeyState::from).collect()
+	fn set_timed_tag(&mut self, tag: LayerTag, duration_ms: u32) {
+		self.timed_tags.push(TimedTag {
+			tag,
+			remaining_ms: duration_ms,
+		});
+		self.tags
+			.set_timed(self.timed_tags.iter().map(|timed| &timed.tag));
 	}
-}
 
-pub struct KeyState<'a> {
-	key: &'a DeviceKey,
-	current_layer: &'a DeviceKeyLayer,
-}
+	fn expire_timed_tags(&mut self, elapsed_ms: u32) {
+		let mut expired = false;
 
-impl<'a> KeyState<'a> {
-	pub fn from(key: &'a DeviceKey) -> Self {
-		KeyState {
-			key: key,
-			current_layer: &key.default_layer,
+		for timed in self.timed_tags.iter_mut() {
+			timed.remaining_ms = timed.remaining_ms.saturating_sub(elapsed_ms);
+			expired |= timed.remaining_ms == 0;
 		}
-	}
-}
 
-pub struct MacroState<'a> {
-	macro_: &'a Macro,
-	current_sequence: CurrentSequence<'a>,
-	trigger: TriggerState,
-	source: MacroSource,
-}
+		if expired {
+			self.timed_tags.retain(|timed| timed.remaining_ms > 0);
+			self.tags
+				.set_timed(self.timed_tags.iter().map(|timed| &timed.tag));
+			self.update_layers();
+		}
+	}
 
-impl<'a> MacroState<'a> {
-	pub fn from(macro_: &'a Macro, source: &KeyState) -> Self {
-		MacroState {
-			macro_,
-			current_sequence: CurrentSequence::Start(SequenceState::from(
-				&macro_.start_sequence,
-				0,
-			)),
-			trigger: TriggerState::Running,
-			source: MacroSource {
-				key: source.key.key_id,
-				layer: source.current_layer.id,
-			},
+	// time until tick has something to do, a host can sleep until then or the next key event,
+	// None means nothing will happen without input
+	pub fn next_deadline_ms(&self) -> Option<u32> {
+		if self.startup_pending || !self.releases.is_empty() {
+			return Some(0);
 		}
+
+		let profile = self.profiles.current();
+		let macros = self
+			.macros
+			.iter()
+			.filter(|m| !m.queued && !m.paused)
+			.filter_map(|m| m.next_deadline_ms(self.profiles.get(m.generation)));
+		let presses = self.pending_presses.iter().filter_map(|pending| {
+			let index = profile
+				.keys
+				.iter()
+				.position(|key| key.key_id == pending.key)?;
+			profile.keys[index]
+				.layer(self.keys[index].layer)
+				.press_macros
+				.iter()
+				.filter_map(|pm| match pm.duration {
+					PressDuration::HeldFor(min_ms) => Some(min_ms.saturating_sub(pending.held_ms)),
+					PressDuration::ReleasedWithin(_) => None,
+				})
+				.min()
+		});
+		let timed_tags = self.timed_tags.iter().map(|timed| timed.remaining_ms);
+		let idle = profile
+			.idle
+			.iter()
+			.zip(self.idle_fired.iter())
+			.filter(|(_, fired)| !**fired)
+			.map(|(idle, _)| idle.timeout_ms.saturating_sub(self.idle_ms));
+
+		macros.chain(presses).chain(timed_tags).chain(idle).min()
+	}
+
+	// changes to tags and resolved layers are only queued once this is called
+	pub fn enable_notifications(&mut self) {
+		self.notifications.enabled = true;
+	}
+
+	// changes to tags and resolved layers since the last call, at most MAX_NOTIFICATIONS of them
+	pub fn drain_notifications(&mut self) -> impl Iterator<Item = Notification> + '_ {
+		core::iter::from_fn(|| self.notifications.queue.pop_front())
+	}
+
+	// true once if changes were dropped because the queue was full, the host then has to read
+	// the tags and layers it cares about again
+	pub fn take_notification_overflow(&mut self) -> bool {
+		core::mem::take(&mut self.notifications.overflowed)
+	}
+
+	pub fn is_tag_active(&self, tag: &LayerTag) -> bool {
+		self.tags.contains(tag)
 	}
 
-	pub fn tick(&mut self, elapsed_ms: u32, events: &mut Vec<&'a ActionEvent>) -> u32 {
-		let mut elapsed_ms = elapsed_ms;
+	pub fn current_layer(&self, key_id: KeyId) -> Option<LayerId> {
+		let profile = self.profiles.current();
+		let index = profile.keys.iter().position(|key| key.key_id == key_id)?;
+		Some(profile.keys[index].layer(self.keys[index].layer).id)
+	}
 
-		while !self.is_finished() && elapsed_ms > 0 {
-			if let CurrentSequence::Start(ref mut seq)
-			| CurrentSequence::Loop(ref mut seq)
-			| CurrentSequence::End(ref mut seq) = self.current_sequence
+	fn update_layers(&mut self) {
+		let active_tags = self.tags.active();
+		let active_others = self.tags.active_others();
+		for id in self.active_tags.difference(&active_tags) {
+			if let Some(tag) = self.tags.name_of(id) {
+				self.notifications
+					.push(|| Notification::TagRemoved(tag.clone()));
+			}
+		}
+		// a tag a new profile refers to gets an id while it stays active
+		for tag in self
+			.active_others
+			.iter()
+			.filter(|tag| !active_others.contains(tag) && !self.tags.contains(tag))
+		{
+			self.notifications
+				.push(|| Notification::TagRemoved(tag.clone()));
+		}
+		for id in active_tags.difference(&self.active_tags) {
+			if let Some(tag) = self
+				.tags
+				.name_of(id)
+				.filter(|tag| !self.active_others.contains(tag))
 			{
-				elapsed_ms = seq.tick(elapsed_ms, events);
+				self.notifications
+					.push(|| Notification::TagAdded(tag.clone()));
+			}
+		}
+		for tag in active_others
+			.iter()
+			.filter(|tag| !self.active_others.contains(tag))
+		{
+			self.notifications
+				.push(|| Notification::TagAdded(tag.clone()));
+		}
+		self.active_tags = active_tags;
+		self.active_others = active_others;
 
-				if seq.is_finished() {
-					self.move_to_next_seq(elapsed_ms);
+		for (ks, key) in self
+			.keys
+			.iter_mut()
+			.zip(self.profiles.current().keys.iter())
+		{
+			let new_index =
+				key.find_active_layer_index(|index, _| ks.conditions[index].evaluate(&self.tags));
+			let new_layer = key.layer(new_index);
 
-					if let CurrentSequence::Loop(seq) = &self.current_sequence {
-						if seq.is_finished() {
-							break;
-						}
-					}
+			if ks.layer_id != new_layer.id {
+				// release macros that no longer have a valid source
+				for macro_ in self.macros.iter_mut().filter(|m| {
+					m.source.key() == Some(key.key_id) && m.source.layer() != Some(new_layer.id)
+				}) {
+					macro_.stop();
 				}
+				self.pending_presses.retain(|p| p.key != key.key_id);
+				self.notifications.push(|| Notification::LayerChanged {
+					key: key.key_id,
+					layer: new_layer.id,
+				});
 			}
+			ks.layer = new_index;
+			ks.layer_id = new_layer.id;
 		}
 
-		elapsed_ms
+		self.update_tag_triggers();
 	}
 
-	pub fn is_finished(&self) -> bool {
-		matches!(self.current_sequence, CurrentSequence::Finished)
-	}
+	// macros started by a trigger are one link further down the chain than whatever changed the
+	// tags. Past MAX_TRIGGER_CHAIN links triggers stop firing, so triggers that keep undoing each
+	// other's tags die out instead of playing forever
+	fn update_tag_triggers(&mut self) {
+		let profile = self.profiles.current();
+		let chain = self.trigger_chain + 1;
+		let mut macros: Vec<MacroState> = Vec::new();
+
+		for (index, trigger) in profile.tag_triggers.iter().enumerate() {
+			let state = &mut self.tag_triggers[index];
+			let active = state.condition.evaluate(&self.tags);
+			if active == state.active {
+				continue;
+			}
+			state.active = active;
 
-	fn stop(&mut self) {
-		self.trigger = TriggerState::Stopping;
+			// whatever the trigger fired for is over
+			for macro_ in self
+				.macros
+				.iter_mut()
+				.filter(|m| m.source == MacroSource::Trigger(index))
+			{
+				macro_.stop();
+			}
+
+			let fires = match trigger.edge {
+				TriggerEdge::Enter => active,
+				TriggerEdge::Exit => !active,
+			};
+			if !fires || chain > MAX_TRIGGER_CHAIN {
+				continue;
+			}
+
+			if let Some((loc, macro_)) =
+				MacroLoc::of(&trigger.macro_, MacroLoc::Trigger(index), profile)
+			{
+				let mut macro_state = MacroState::new(
+					loc,
+					macro_,
+					MacroSource::Trigger(index),
+					self.profiles.generation,
+					self.rng.fork(),
+				);
+				macro_state.chain = chain;
+				if matches!(macro_.trigger_mode, TriggerMode::OnRelease) {
+					macro_state.stop();
+				}
+				macros.push(macro_state);
+			}
+		}
+
+		self.start_macros(macros);
 	}
 
-	fn move_to_next_seq(&mut self, elapsed_ms: u32) {
-		match self.current_sequence {
-			CurrentSequence::Start(_) => match self.trigger {
-				TriggerState::Running => self.move_to_loop(elapsed_ms),
-				TriggerState::Stopping => self.move_to_end(elapsed_ms),
-			},
-			CurrentSequence::Loop(_) => match self.trigger {
-				TriggerState::Running => self.move_to_loop(elapsed_ms),
-				TriggerState::Stopping => self.move_to_end(elapsed_ms),
-			},
-			CurrentSequence::End(_) => {
-				self.current_sequence = CurrentSequence::Finished;
+	// ticks the last elapsed_ms of a tick that is tick_ms long, profile is the one the macro was
+	// started from
+	fn tick_macro(
+		macro_: &mut MacroState,
+		tick_ms: u32,
+		elapsed_ms: u32,
+		events: &mut Vec<TimedEvent>,
+		profile: &KeyboardProfile,
+		rng: &mut Rng,
+		spawned: &mut Vec<(MacroState, u32)>,
+	) {
+		let mut elapsed_ms = Self::tick_window(macro_, tick_ms, elapsed_ms, events, profile);
+
+		while let Some(call) = macro_.take_call() {
+			match MacroLoc::library(call.id, profile) {
+				Some((loc, callee)) if call.depth < MAX_CALL_DEPTH => {
+					let callee = MacroState::called(loc, callee, &call, rng.fork());
+
+					match call.mode {
+						CallMode::Blocking => macro_.attach_call(callee),
+						CallMode::Detached => spawned.push((callee, elapsed_ms)),
+					}
+				}
+				// unknown macros and calls nested too deeply are skipped
+				_ => {}
 			}
-			CurrentSequence::Finished => {}
+
+			elapsed_ms = Self::tick_window(macro_, tick_ms, elapsed_ms, events, profile);
 		}
 	}
 
-	fn move_to_loop(&mut self, elapsed_ms: u32) {
-		self.current_sequence =
-			CurrentSequence::Loop(SequenceState::from(&self.macro_.loop_sequence, elapsed_ms));
+	fn tick_window(
+		macro_: &mut MacroState,
+		tick_ms: u32,
+		elapsed_ms: u32,
+		events: &mut Vec<TimedEvent>,
+		profile: &KeyboardProfile,
+	) -> u32 {
+		let start = events.len();
+		let remaining_ms = macro_.tick(profile, elapsed_ms, events);
+		shift_events(&mut events[start..], tick_ms - elapsed_ms);
+
+		remaining_ms
 	}
 
-	fn move_to_end(&mut self, elapsed_ms: u32) {
-		self.current_sequence =
-			CurrentSequence::End(SequenceState::from(&self.macro_.end_sequence, elapsed_ms));
+	fn resolve_held_presses(&mut self, elapsed_ms: u32) {
+		let profile = self.profiles.current();
+		let generation = self.profiles.generation;
+		let mut macros: Vec<MacroState> = Vec::new();
+
+		for pending in self.pending_presses.iter_mut() {
+			pending.held_ms += elapsed_ms;
+
+			if let Some(index) = profile
+				.keys
+				.iter()
+				.position(|key| key.key_id == pending.key)
+			{
+				let key = &self.keys[index];
+				let layer = profile.keys[index].layer(key.layer);
+
+				if let Some((i, pm)) = layer.press_macros.iter().enumerate().find(
+					|(_, pm)| matches!(pm.duration, PressDuration::HeldFor(min_ms) if pending.held_ms >= min_ms),
+				) {
+					let inline = MacroLoc::Key(index, key.layer, layer.macros.len() + i);
+					if let Some((loc, macro_)) = MacroLoc::of(&pm.macro_, inline, profile) {
+						let source = MacroSource::Key {
+							key: pending.key,
+							layer: layer.id,
+						};
+						macros.push(MacroState::new(
+							loc,
+							macro_,
+							source,
+							generation,
+							self.rng.fork(),
+						));
+					}
+					pending.resolved = true;
+				}
+			}
+		}
+
+		self.pending_presses.retain(|p| !p.resolved);
+		self.start_macros(macros);
 	}
-}
 
-pub struct MacroSource {
-	key: KeyId,
-	layer: LayerId,
-}
+	fn start_macros(&mut self, macros: Vec<MacroState>) {
+		let mut admitted: Vec<MacroState> = Vec::new();
 
-pub struct SequenceState<'a> {
-	pending: Vec<&'a Action>,
-	elapsed_ms: u32,
-}
+		for mut macro_ in macros {
+			let channel = match self.profiles.macro_of(&macro_).and_then(|m| m.play_channel) {
+				Some(channel) => channel,
+				None => {
+					admitted.push(macro_);
+					continue;
+				}
+			};
+			// macros admitted earlier in this batch play on the channel as well
+			let playing = self.count_playing(channel)
+				+ admitted
+					.iter()
+					.filter(|m| {
+						self.profiles
+							.macro_of(m)
+							.is_some_and(|m| m.play_channel == Some(channel))
+					})
+					.count();
+
+			match self.profiles.get(macro_.generation).channel_policy(channel) {
+				ChannelPolicy::Queue if playing > 0 => {
+					macro_.queued = true;
+					self.macros.push(macro_);
+				}
+				ChannelPolicy::IgnoreNew if playing > 0 => {}
+				ChannelPolicy::MaxConcurrent(max) if playing >= max as usize => {}
+				_ => admitted.push(macro_),
+			}
+		}
 
-impl<'a> SequenceState<'a> {
-	fn from(sequence: &'a Sequence, elapsed_ms: u32) -> Self {
-		SequenceState {
-			pending: sequence.actions.iter().rev().collect(),
-			elapsed_ms,
+		for macro_ in admitted.iter() {
+			self.cut_channels(macro_.generation, macro_.loc);
 		}
+		self.macros.extend(admitted);
 	}
 
-	pub fn tick(&mut self, elapsed_ms: u32, events: &mut Vec<&'a ActionEvent>) -> u32 {
-		self.elapsed_ms += elapsed_ms;
+	fn start_queued_macros(&mut self) {
+		for index in 0..self.macros.len() {
+			if !self.macros[index].queued {
+				continue;
+			}
 
-		while let Some(action) = self.pending.pop() {
-			if action.predelay_ms <= self.elapsed_ms {
-				events.push(&action.action_event);
-				self.elapsed_ms -= action.predelay_ms;
-			} else {
-				self.pending.push(action);
-				return 0;
+			let free = match self
+				.profiles
+				.macro_of(&self.macros[index])
+				.and_then(|m| m.play_channel)
+			{
+				Some(channel) => self.count_playing(channel) == 0,
+				None => true,
+			};
+
+			if free {
+				let (generation, loc) = (self.macros[index].generation, self.macros[index].loc);
+				self.cut_channels(generation, loc);
+				self.macros[index].queued = false;
 			}
 		}
+	}
 
-		self.elapsed_ms
+	fn count_playing(&self, channel: Channel) -> usize {
+		self.macros
+			.iter()
+			.filter(|m| {
+				!m.queued
+					&& self
+						.profiles
+						.macro_of(m)
+						.is_some_and(|m| m.play_channel == Some(channel))
+			})
+			.count()
 	}
 
-	pub fn is_finished(&self) -> bool {
-		self.pending.is_empty()
+	// stops what plays on the channels the macro at loc cuts
+	fn cut_channels(&mut self, generation: u32, loc: MacroLoc) {
+		let cutter = match loc.resolve(self.profiles.get(generation)) {
+			Some(cutter) => cutter,
+			None => return,
+		};
+
+		for macro_ in self.macros.iter_mut().filter(|m| {
+			!m.queued
+				&& self
+					.profiles
+					.macro_of(m)
+					.and_then(|m| m.play_channel)
+					.is_some_and(|channel| cutter.cut_channels.contains(&channel))
+		}) {
+			self.releases.extend(macro_.stop_with_mode(cutter.cut_mode));
+		}
+	}
+
+	fn drop_retired_profiles(&mut self) {
+		let macros = &self.macros;
+		self.profiles
+			.retired
+			.retain(|(generation, _)| macros.iter().any(|m| m.generation == *generation));
+	}
+
+	// conditions already matching when the profile is loaded don't fire
+	fn map_triggers_from_profile(
+		profile: &KeyboardProfile,
+		tags: &mut TagList,
+	) -> Vec<TagTriggerState> {
+		profile
+			.tag_triggers
+			.iter()
+			.map(|trigger| {
+				let condition = trigger.condition.compile(tags);
+				TagTriggerState {
+					active: condition.evaluate(tags),
+					condition,
+				}
+			})
+			.collect()
+	}
+
+	fn map_keys_from_profile(profile: &KeyboardProfile, tags: &mut TagList) -> Vec<KeyState> {
+		profile
+			.keys
+			.iter()
+			.map(|key| KeyState::from(key, tags))
+			.collect()
 	}
 }
 
-pub enum CurrentSequence<'a> {
-	Start(SequenceState<'a>),
-	Loop(SequenceState<'a>),
-	End(SequenceState<'a>),
-	Finished,
+// the current profile, and the ones it replaced while macros started from them are finishing.
+// Macros keep the generation of the profile they came from
+struct Profiles<P> {
+	current: P,
+	generation: u32,
+	retired: Vec<(u32, P)>,
 }
 
-impl<'a> fmt::Debug for CurrentSequence<'a> {
-	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-		match *self {
-			CurrentSequence::Start(_) => write!(f, "Start"),
-			CurrentSequence::Loop(_) => write!(f, "Loop"),
-			CurrentSequence::End(_) => write!(f, "End"),
-			CurrentSequence::Finished => write!(f, "Finished"),
-		}
+impl<P: Borrow<KeyboardProfile>> Profiles<P> {
+	fn current(&self) -> &KeyboardProfile {
+		self.current.borrow()
 	}
-}
 
-#[derive(Debug)]
+	fn get(&self, generation: u32) -> &KeyboardProfile {
+		self.retired
+			.iter()
+			.find(|(retired, _)| *retired == generation)
+			.map_or(self.current(), |(_, profile)| profile.borrow())
+	}
+
+	fn macro_of(&self, macro_: &MacroState) -> Option<&Macro> {
+		macro_.loc.resolve(self.get(macro_.generation))
+	}
+
+	fn replace(&mut self, profile: P) {
+		let old = core::mem::replace(&mut self.current, profile);
+		self.retired.push((self.generation, old));
+		self.generation = self.generation.wrapping_add(1);
+	}
+}
+
+pub struct KeyState {
+	// index into DeviceKey::all_layers
+	layer: usize,
+	// id of that layer, carried over to a new profile so only real layer changes are reported
+	layer_id: LayerId,
+	// one per tagged layer of the key
+	conditions: Vec<TagCondition>,
+}
+
+impl KeyState {
+	pub fn from(key: &DeviceKey, tags: &mut TagList) -> Self {
+		KeyState {
+			layer: 0,
+			layer_id: key.default_layer.id,
+			conditions: key
+				.layers
+				.iter()
+				.map(|layer| layer.compile_condition(tags))
+				.collect(),
+		}
+	}
+}
+
+pub struct PendingPress {
+	key: KeyId,
+	held_ms: u32,
+	resolved: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ProfileUpdate {
+	// running macros are released, like the key was let go
+	StopMacros,
+	// key macros found on the same key and layer of the new profile with the same id and
+	// definition keep playing, including what they called
+	KeepUnchangedMacros,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Notification {
+	TagAdded(LayerTag),
+	TagRemoved(LayerTag),
+	LayerChanged { key: KeyId, layer: LayerId },
+}
+
+pub const MAX_NOTIFICATIONS: usize = 16;
+
+#[derive(Default)]
+pub struct NotificationQueue {
+	enabled: bool,
+	queue: heapless::Deque<Notification, MAX_NOTIFICATIONS>,
+	overflowed: bool,
+}
+
+impl NotificationQueue {
+	// built lazily so nothing is cloned while notifications are off
+	fn push(&mut self, notification: impl FnOnce() -> Notification) {
+		if self.enabled && self.queue.push_back(notification()).is_err() {
+			self.overflowed = true;
+		}
+	}
+}
+
+pub struct TagTriggerState {
+	condition: TagCondition,
+	active: bool,
+}
+
+pub struct TimedTag {
+	tag: LayerTag,
+	remaining_ms: u32,
+}
+
+pub struct MacroState {
+	id: MacroId,
+	// where the macro is defined in the profile of its generation
+	loc: MacroLoc,
+	generation: u32,
+	current_sequence: CurrentSequence,
+	trigger: TriggerState,
+	source: MacroSource,
+	loops_completed: u32,
+	loop_elapsed_ms: u32,
+	rng: Rng,
+	call: Option<Box<MacroState>>,
+	pending_call: Option<(MacroId, CallMode)>,
+	depth: u8,
+	// how many tag triggers in a row led to this macro, called macros share it with their caller
+	chain: u8,
+	// waiting for its play channel to become free
+	queued: bool,
+	paused: bool,
+	held: HeldInput,
+}
+
+impl MacroState {
+	// macro_ is what loc resolves to in the profile of the generation
+	pub fn new(
+		loc: MacroLoc,
+		macro_: &Macro,
+		source: MacroSource,
+		generation: u32,
+		mut rng: Rng,
+	) -> Self {
+		MacroState {
+			id: macro_.id,
+			loc,
+			generation,
+			current_sequence: CurrentSequence::Start(SequenceState::with_timing(
+				&macro_.start_sequence,
+				macro_.speed_multiplier,
+				&mut rng,
+			)),
+			trigger: TriggerState::Running,
+			source,
+			loops_completed: 0,
+			loop_elapsed_ms: 0,
+			rng,
+			call: None,
+			pending_call: None,
+			depth: 0,
+			chain: 0,
+			queued: false,
+			paused: false,
+			held: HeldInput::default(),
+		}
+	}
+
+	// called macros share the source and profile of their caller, so releasing the key stops them
+	// as well
+	fn called(loc: MacroLoc, macro_: &Macro, call: &CallRequest, rng: Rng) -> Self {
+		let mut macro_state = MacroState::new(loc, macro_, call.source, call.generation, rng);
+		macro_state.depth = call.depth + 1;
+		macro_state.chain = call.chain;
+		if call.stopping {
+			macro_state.stop();
+		}
+		macro_state
+	}
+
+	// event offsets are from the start of elapsed_ms, profile is the one of the macro's generation
+	pub fn tick(
+		&mut self,
+		profile: &KeyboardProfile,
+		elapsed_ms: u32,
+		events: &mut Vec<TimedEvent>,
+	) -> u32 {
+		let macro_ = match self.loc.resolve(profile) {
+			Some(macro_) => macro_,
+			None => {
+				self.current_sequence = CurrentSequence::Finished;
+				return elapsed_ms;
+			}
+		};
+
+		let start = events.len();
+		let remaining_ms = self.tick_sequences(macro_, profile, elapsed_ms, events);
+
+		// events from called macros are already attributed to them
+		for timed in events[start..].iter_mut() {
+			timed.macro_id.get_or_insert(self.id);
+			timed.key_id = timed.key_id.or(self.source.key());
+		}
+		self.held.track(&events[start..]);
+
+		remaining_ms
+	}
+
+	fn tick_sequences(
+		&mut self,
+		macro_: &Macro,
+		profile: &KeyboardProfile,
+		tick_ms: u32,
+		events: &mut Vec<TimedEvent>,
+	) -> u32 {
+		let mut elapsed_ms = tick_ms;
+		// time left when a loop pass started in this call, a pass that took none of it ends the
+		// call so a loop of zero delay actions can't spin forever
+		let mut loop_pass_from_ms = None;
+
+		// keeps going with no time left as long as something is due right away, so tick(0) plays
+		// zero delay actions and moves past empty sequences
+		while !self.is_finished()
+			&& (elapsed_ms > 0 || self.deadline_ms(macro_, profile) == Some(0))
+		{
+			// a blocking call runs to completion before the caller continues
+			if let Some(call) = self.call.as_mut() {
+				let start = events.len();
+				let remaining_ms = call.tick(profile, elapsed_ms, events);
+				let finished = call.is_finished();
+				shift_events(&mut events[start..], tick_ms - elapsed_ms);
+
+				if let CurrentSequence::Loop(_) = self.current_sequence {
+					self.loop_elapsed_ms += elapsed_ms - remaining_ms;
+				}
+				elapsed_ms = remaining_ms;
+
+				if !finished {
+					break;
+				}
+				self.call = None;
+				continue;
+			}
+
+			// waiting for the keyboard state to start the called macro
+			if self.pending_call.is_some() {
+				break;
+			}
+
+			let (seq, sequence) = match self.current_sequence {
+				CurrentSequence::Start(ref mut seq) => (seq, &macro_.start_sequence),
+				CurrentSequence::Loop(ref mut seq) => (seq, &macro_.loop_sequence),
+				CurrentSequence::End(ref mut seq) => (seq, &macro_.end_sequence),
+				CurrentSequence::Finished => break,
+			};
+
+			let start = events.len();
+			let remaining_ms = seq.tick(sequence, elapsed_ms, events);
+			let finished = seq.is_finished();
+			shift_events(&mut events[start..], tick_ms - elapsed_ms);
+			let called = seq.take_call();
+
+			if let CurrentSequence::Loop(_) = self.current_sequence {
+				self.loop_elapsed_ms += elapsed_ms - remaining_ms;
+			}
+			elapsed_ms = remaining_ms;
+
+			let mut empty_pass = false;
+			if finished {
+				let pass_from_ms = loop_pass_from_ms;
+				// the remaining time is carried into the next sequence by this loop
+				self.move_to_next_seq(macro_);
+				loop_pass_from_ms = match self.current_sequence {
+					CurrentSequence::Loop(_) => Some(elapsed_ms),
+					_ => None,
+				};
+				empty_pass = pass_from_ms == Some(elapsed_ms) && loop_pass_from_ms.is_some();
+			}
+
+			if called.is_some() {
+				self.pending_call = called;
+				break;
+			}
+
+			if empty_pass {
+				break;
+			}
+			if finished {
+				if let CurrentSequence::Loop(seq) = &self.current_sequence {
+					if seq.is_finished() {
+						// an empty loop waits instead, and the wait counts towards max_loop_ms
+						let max_loop_ms = match macro_.max_loop_ms {
+							Some(max_loop_ms) => max_loop_ms,
+							None => break,
+						};
+						let wait_ms =
+							elapsed_ms.min(max_loop_ms.saturating_sub(self.loop_elapsed_ms));
+						self.loop_elapsed_ms += wait_ms;
+						elapsed_ms -= wait_ms;
+
+						if self.loop_elapsed_ms < max_loop_ms {
+							break;
+						}
+					}
+				}
+			}
+		}
+
+		elapsed_ms
+	}
+
+	pub fn is_finished(&self) -> bool {
+		matches!(self.current_sequence, CurrentSequence::Finished)
+	}
+
+	pub fn next_deadline_ms(&self, profile: &KeyboardProfile) -> Option<u32> {
+		self.deadline_ms(self.loc.resolve(profile)?, profile)
+	}
+
+	fn deadline_ms(&self, macro_: &Macro, profile: &KeyboardProfile) -> Option<u32> {
+		if let Some(call) = self.call.as_ref() {
+			return call.next_deadline_ms(profile);
+		}
+		if self.pending_call.is_some() {
+			return Some(0);
+		}
+
+		match &self.current_sequence {
+			CurrentSequence::Start(seq) | CurrentSequence::End(seq) => Some(seq.next_deadline_ms()),
+			CurrentSequence::Loop(seq)
+				if seq.is_finished() && self.loops_until_released(macro_) =>
+			{
+				None
+			}
+			CurrentSequence::Loop(seq) if seq.is_finished() && self.is_running() => {
+				match macro_.max_loop_ms {
+					Some(max_loop_ms) => Some(max_loop_ms.saturating_sub(self.loop_elapsed_ms)),
+					None => Some(0),
+				}
+			}
+			CurrentSequence::Loop(seq) => Some(seq.next_deadline_ms()),
+			CurrentSequence::Finished => None,
+		}
+	}
+
+	// an empty loop without limits only ends when the macro is released
+	fn loops_until_released(&self, macro_: &Macro) -> bool {
+		self.is_running()
+			&& macro_.max_loops.is_none()
+			&& macro_.max_loop_ms.is_none()
+			&& !matches!(macro_.trigger_mode, TriggerMode::OneShot)
+	}
+
+	pub fn is_running(&self) -> bool {
+		matches!(self.trigger, TriggerState::Running) && !self.is_finished()
+	}
+
+	fn stop(&mut self) {
+		self.trigger = TriggerState::Stopping;
+		self.paused = false;
+
+		if let Some(call) = self.call.as_mut() {
+			call.stop();
+		}
+	}
+
+	fn stop_with_mode(&mut self, mode: StopMode) -> Vec<TimedEvent> {
+		match mode {
+			StopMode::Graceful => {
+				self.stop();
+				Vec::new()
+			}
+			StopMode::Abort => {
+				let (macro_id, key_id) = (self.id, self.source.key());
+				self.abort()
+					.into_iter()
+					.map(|event| TimedEvent {
+						event,
+						offset_ms: 0,
+						macro_id: Some(macro_id),
+						key_id,
+					})
+					.collect()
+			}
+		}
+	}
+
+	// finishes right away and returns the releases for anything still held, including what
+	// called macros pressed
+	fn abort(&mut self) -> Vec<ActionEvent> {
+		self.current_sequence = CurrentSequence::Finished;
+		self.call = None;
+		self.pending_call = None;
+
+		self.held.release_all()
+	}
+
+	// the innermost called macro is the only one that can be waiting on a call
+	fn take_call(&mut self) -> Option<CallRequest> {
+		if let Some(call) = self.call.as_mut() {
+			return call.take_call();
+		}
+
+		self.pending_call.take().map(|(id, mode)| CallRequest {
+			id,
+			mode,
+			depth: self.depth,
+			chain: self.chain,
+			generation: self.generation,
+			source: self.source,
+			stopping: !matches!(self.trigger, TriggerState::Running),
+		})
+	}
+
+	fn attach_call(&mut self, callee: MacroState) {
+		match self.call.as_mut() {
+			Some(call) => call.attach_call(callee),
+			None => self.call = Some(Box::new(callee)),
+		}
+	}
+
+	fn move_to_next_seq(&mut self, macro_: &Macro) {
+		match self.current_sequence {
+			CurrentSequence::Start(_) => match self.trigger {
+				TriggerState::Running if !self.loop_limit_reached(macro_) => {
+					self.move_to_loop(macro_)
+				}
+				_ => self.move_to_end(macro_),
+			},
+			CurrentSequence::Loop(_) => {
+				self.loops_completed += 1;
+
+				match self.trigger {
+					TriggerState::Running if !self.loop_limit_reached(macro_) => {
+						self.move_to_loop(macro_)
+					}
+					_ => self.move_to_end(macro_),
+				}
+			}
+			CurrentSequence::End(_) => {
+				self.current_sequence = CurrentSequence::Finished;
+			}
+			CurrentSequence::Finished => {}
+		}
+	}
+
+	// a loop pass that has started always plays out, limits are only checked between passes
+	fn loop_limit_reached(&self, macro_: &Macro) -> bool {
+		let count_reached = match macro_.max_loops {
+			Some(max_loops) => self.loops_completed >= max_loops,
+			None => false,
+		};
+		let duration_reached = match macro_.max_loop_ms {
+			Some(max_loop_ms) => self.loop_elapsed_ms >= max_loop_ms,
+			None => false,
+		};
+		let one_shot_done =
+			matches!(macro_.trigger_mode, TriggerMode::OneShot) && self.loops_completed >= 1;
+
+		count_reached || duration_reached || one_shot_done
+	}
+
+	fn move_to_loop(&mut self, macro_: &Macro) {
+		self.current_sequence = CurrentSequence::Loop(SequenceState::with_timing(
+			&macro_.loop_sequence,
+			macro_.speed_multiplier,
+			&mut self.rng,
+		));
+	}
+
+	fn move_to_end(&mut self, macro_: &Macro) {
+		self.current_sequence = CurrentSequence::End(SequenceState::with_timing(
+			&macro_.end_sequence,
+			macro_.speed_multiplier,
+			&mut self.rng,
+		));
+	}
+
+	// whether the macro at loc in profile, and what this one called, are defined the same way as
+	// in the profile the macro plays from
+	fn is_unchanged_in(
+		&self,
+		from: &KeyboardProfile,
+		profile: &KeyboardProfile,
+		loc: MacroLoc,
+	) -> bool {
+		loc.resolve(profile)
+			.is_some_and(|macro_| self.loc.resolve(from) == Some(macro_))
+			&& self.call.as_ref().is_none_or(|call| {
+				MacroLoc::library(call.id, profile)
+					.is_some_and(|(loc, _)| call.is_unchanged_in(from, profile, loc))
+			})
+	}
+
+	// moves an unchanged macro over to the same definition in another profile
+	fn rebind(&mut self, profile: &KeyboardProfile, loc: MacroLoc, generation: u32) {
+		self.loc = loc;
+		self.generation = generation;
+
+		if let Some(call) = self.call.as_mut() {
+			if let Some((loc, _)) = MacroLoc::library(call.id, profile) {
+				call.rebind(profile, loc, generation);
+			}
+		}
+	}
+}
+
+struct CallRequest {
+	id: MacroId,
+	mode: CallMode,
+	depth: u8,
+	chain: u8,
+	generation: u32,
+	source: MacroSource,
+	stopping: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MacroSource {
+	Key { key: KeyId, layer: LayerId },
+	// index into the profile's tag triggers
+	Trigger(usize),
+	Startup,
+	// index into the profile's idle actions
+	Idle(usize),
+}
+
+impl MacroSource {
+	pub fn key(&self) -> Option<KeyId> {
+		match self {
+			MacroSource::Key { key, .. } => Some(*key),
+			_ => None,
+		}
+	}
+
+	pub fn layer(&self) -> Option<LayerId> {
+		match self {
+			MacroSource::Key { layer, .. } => Some(*layer),
+			_ => None,
+		}
+	}
+}
+
+pub struct SequenceState {
+	// delays of the actions still to play, in reverse so the next one is last. They are always
+	// the tail of the sequence
+	pending: Vec<u32>,
+	elapsed_ms: u32,
+	call: Option<(MacroId, CallMode)>,
+}
+
+impl SequenceState {
+	#[cfg(test)]
+	fn from(sequence: &Sequence, elapsed_ms: u32) -> Self {
+		let mut state = SequenceState::with_timing(sequence, None, &mut Rng::default());
+		state.elapsed_ms = elapsed_ms;
+		state
+	}
+
+	// delays are randomized once, when the sequence is entered
+	fn with_timing(sequence: &Sequence, speed_multiplier: Option<f32>, rng: &mut Rng) -> Self {
+		SequenceState {
+			pending: sequence
+				.actions
+				.iter()
+				.rev()
+				.map(|action| scheduled_delay(action, speed_multiplier, rng))
+				.collect(),
+			elapsed_ms: 0,
+			call: None,
+		}
+	}
+
+	// sequence is the one the state was made from
+	pub fn tick(
+		&mut self,
+		sequence: &Sequence,
+		elapsed_ms: u32,
+		events: &mut Vec<TimedEvent>,
+	) -> u32 {
+		self.elapsed_ms += elapsed_ms;
+
+		while let Some(delay_ms) = self.pending.pop() {
+			if delay_ms > self.elapsed_ms {
+				self.pending.push(delay_ms);
+				return 0;
+			}
+			self.elapsed_ms -= delay_ms;
+
+			let action = match sequence.actions.iter().rev().nth(self.pending.len()) {
+				Some(action) => action,
+				None => continue,
+			};
+			// whatever is left of elapsed_ms passed after the action
+			events.push(TimedEvent {
+				event: action.action_event.clone(),
+				offset_ms: elapsed_ms.saturating_sub(self.elapsed_ms),
+				macro_id: None,
+				key_id: None,
+			});
+
+			if let ActionEvent::Call(id, mode) = action.action_event {
+				// hand the remaining time back so the called macro can be started first
+				self.call = Some((id, mode));
+				let remaining_ms = self.elapsed_ms;
+				self.elapsed_ms = 0;
+				return remaining_ms;
+			}
+		}
+
+		self.elapsed_ms
+	}
+
+	pub fn is_finished(&self) -> bool {
+		self.pending.is_empty()
+	}
+
+	// an empty sequence is due right away, so the macro can move on
+	fn next_deadline_ms(&self) -> u32 {
+		match self.pending.last() {
+			Some(delay_ms) => delay_ms.saturating_sub(self.elapsed_ms),
+			None => 0,
+		}
+	}
+
+	fn take_call(&mut self) -> Option<(MacroId, CallMode)> {
+		self.call.take()
+	}
+}
+
+#[derive(Clone)]
+pub struct TimedEvent {
+	pub event: ActionEvent,
+	// from the start of the tick
+	pub offset_ms: u32,
+	pub macro_id: Option<MacroId>,
+	pub key_id: Option<KeyId>,
+}
+
+impl TimedEvent {
+	// what a sink gets to see of this event, if anything
+	pub fn output(self) -> Option<TimedOutput> {
+		let event = match self.event {
+			ActionEvent::Keyboard(event) => OutputEvent::Keyboard(event),
+			ActionEvent::Mouse(event) => OutputEvent::Mouse(event),
+			_ => return None,
+		};
+
+		Some(TimedOutput {
+			event,
+			offset_ms: self.offset_ms,
+			macro_id: self.macro_id,
+			key_id: self.key_id,
+		})
+	}
+}
+
+fn shift_events(events: &mut [TimedEvent], offset_ms: u32) {
+	for timed in events.iter_mut() {
+		timed.offset_ms += offset_ms;
+	}
+}
+
+// keys and buttons pressed by emitted events and not released yet
+#[derive(Default)]
+pub struct HeldInput {
+	keys: Vec<KeyboardKey>,
+	buttons: Vec<MouseButton>,
+}
+
+impl HeldInput {
+	fn track(&mut self, events: &[TimedEvent]) {
+		for timed in events {
+			match &timed.event {
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(key)) if !self.keys.contains(key) => {
+					self.keys.push(*key);
+				}
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(key)) => {
+					self.keys.retain(|k| k != key);
+				}
+				ActionEvent::Mouse(MouseEvent::ButtonDown(button))
+					if !self.buttons.contains(button) =>
+				{
+					self.buttons.push(*button);
+				}
+				ActionEvent::Mouse(MouseEvent::ButtonUp(button)) => {
+					self.buttons.retain(|b| b != button);
+				}
+				_ => {}
+			}
+		}
+	}
+
+	fn release_all(&mut self) -> Vec<ActionEvent> {
+		let keys = self
+			.keys
+			.drain(..)
+			.map(|key| ActionEvent::Keyboard(KeyboardEvent::KeyUp(key)));
+		let buttons = self
+			.buttons
+			.drain(..)
+			.map(|button| ActionEvent::Mouse(MouseEvent::ButtonUp(button)));
+
+		keys.chain(buttons).collect()
+	}
+}
+
+fn scheduled_delay(action: &Action, speed_multiplier: Option<f32>, rng: &mut Rng) -> u32 {
+	let delay_ms = match action.jitter_ms {
+		Some(jitter_ms) => (action.predelay_ms as i64 + rng.next_offset(jitter_ms)).max(0) as u32,
+		None => action.predelay_ms,
+	};
+
+	match speed_multiplier {
+		Some(speed) if speed > 0.0 => (delay_ms as f32 / speed) as u32,
+		_ => delay_ms,
+	}
+}
+
+pub enum CurrentSequence {
+	Start(SequenceState),
+	Loop(SequenceState),
+	End(SequenceState),
+	Finished,
+}
+
+impl fmt::Debug for CurrentSequence {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match *self {
+			CurrentSequence::Start(_) => write!(f, "Start"),
+			CurrentSequence::Loop(_) => write!(f, "Loop"),
+			CurrentSequence::End(_) => write!(f, "End"),
+			CurrentSequence::Finished => write!(f, "Finished"),
+		}
+	}
+}
+
+#[derive(Debug)]
 pub enum TriggerState {
 	Running,
 	Stopping,
@@ -282,6 +1801,9 @@ pub enum TriggerState {
 #[cfg(test)]
 mod tests {
 	use super::*;
+	use crate::sink::TraceSink;
+	use crate::tag_expr::TagExpr;
+	use crate::MAX_TAGS;
 	use alloc::vec;
 
 	// ------- SEQUENCE TESTS --------
@@ -291,6 +1813,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 1000,
+				jitter_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -298,13 +1821,13 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.elapsed_ms, 0);
 
-		state.tick(50, &mut vec![]);
+		state.tick(&sequence, 50, &mut vec![]);
 		assert_eq!(state.elapsed_ms, 50);
 
-		state.tick(100, &mut vec![]);
+		state.tick(&sequence, 100, &mut vec![]);
 		assert_eq!(state.elapsed_ms, 150);
 
-		state.tick(200, &mut vec![]);
+		state.tick(&sequence, 200, &mut vec![]);
 		assert_eq!(state.elapsed_ms, 350);
 	}
 
@@ -313,6 +1836,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 1000,
+				jitter_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -320,16 +1844,16 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(100, &mut vec![]);
+		state.tick(&sequence, 100, &mut vec![]);
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(100, &mut vec![]);
+		state.tick(&sequence, 100, &mut vec![]);
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(200, &mut vec![]);
+		state.tick(&sequence, 200, &mut vec![]);
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(599, &mut vec![]);
+		state.tick(&sequence, 599, &mut vec![]);
 		assert_eq!(state.pending.len(), 1);
 	}
 
@@ -339,10 +1863,12 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -351,10 +1877,10 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.pending.len(), 2);
 
-		state.tick(99, &mut vec![]);
+		state.tick(&sequence, 99, &mut vec![]);
 		assert_eq!(state.pending.len(), 2);
 
-		state.tick(1, &mut vec![]);
+		state.tick(&sequence, 1, &mut vec![]);
 		assert_eq!(state.pending.len(), 1);
 	}
 
@@ -364,10 +1890,12 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -376,10 +1904,10 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.is_finished(), false);
 
-		state.tick(299, &mut vec![]);
+		state.tick(&sequence, 299, &mut vec![]);
 		assert_eq!(state.is_finished(), false);
 
-		state.tick(1, &mut vec![]);
+		state.tick(&sequence, 1, &mut vec![]);
 		assert_eq!(state.is_finished(), true);
 	}
 
@@ -388,6 +1916,7 @@ mod tests {
 		let sequence = Sequence {
 			actions: vec![Action {
 				predelay_ms: 0,
+				jitter_ms: None,
 				action_event: ActionEvent::None,
 			}],
 		};
@@ -395,7 +1924,7 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.pending.len(), 1);
 
-		state.tick(0, &mut vec![]);
+		state.tick(&sequence, 0, &mut vec![]);
 		assert_eq!(state.pending.len(), 0);
 	}
 
@@ -405,14 +1934,17 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 200,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				},
 			],
@@ -421,7 +1953,7 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		assert_eq!(state.pending.len(), 3);
 
-		state.tick(400, &mut vec![]);
+		state.tick(&sequence, 400, &mut vec![]);
 		assert_eq!(state.pending.len(), 0);
 	}
 
@@ -431,14 +1963,17 @@ mod tests {
 			actions: vec![
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
 				},
 				Action {
 					predelay_ms: 200,
+					jitter_ms: None,
 					action_event: ActionEvent::Mouse(MouseEvent::Move(0, 0)),
 				},
 				Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
 				},
 			],
@@ -447,29 +1982,63 @@ mod tests {
 		let mut state = SequenceState::from(&sequence, 0);
 		let mut events = vec![];
 
-		state.tick(400, &mut events);
+		state.tick(&sequence, 400, &mut events);
 		assert_eq!(events.len(), 3);
 
 		assert!(matches!(
-			events[0],
+			events[0].event,
 			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A))
 		));
 		assert!(matches!(
-			events[1],
+			events[1].event,
 			ActionEvent::Mouse(MouseEvent::Move(0, 0))
 		));
 		assert!(matches!(
-			events[2],
+			events[2].event,
 			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
 		));
 	}
 
-	// ------- MACRO TESTS --------
-
 	#[test]
-	fn macro_moves_to_loop_sequence() {
-		let device_key = new_test_device_key(
-			KeyId::new(1),
+	fn sequence_jitter_stays_in_range() {
+		let sequence = Sequence {
+			actions: vec![Action {
+				predelay_ms: 100,
+				jitter_ms: Some(20),
+				action_event: ActionEvent::None,
+			}],
+		};
+
+		let mut rng = Rng::new(1);
+		for _ in 0..100 {
+			let state = SequenceState::with_timing(&sequence, None, &mut rng);
+			assert!((80..=120).contains(&state.pending[0]));
+		}
+	}
+
+	#[test]
+	fn sequence_speed_multiplier_scales_delays() {
+		let sequence = Sequence {
+			actions: vec![Action {
+				predelay_ms: 100,
+				jitter_ms: None,
+				action_event: ActionEvent::None,
+			}],
+		};
+
+		let state = SequenceState::with_timing(&sequence, Some(2.0), &mut Rng::default());
+		assert_eq!(state.pending[0], 50);
+
+		let state = SequenceState::with_timing(&sequence, Some(0.5), &mut Rng::default());
+		assert_eq!(state.pending[0], 200);
+	}
+
+	// ------- MACRO TESTS --------
+
+	#[test]
+	fn macro_moves_to_loop_sequence() {
+		let device_key = new_test_device_key(
+			KeyId::new(1),
 			vec![new_test_macro(
 				MacroId::new(1),
 				Some(Channel::new(1)),
@@ -477,14 +2046,14 @@ mod tests {
 			)],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Start(_)
 		));
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -502,16 +2071,16 @@ mod tests {
 			)],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
 		));
 
-		macro_state.tick(200, &mut vec![]);
+		macro_state.tick(&profile, 200, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -526,6 +2095,7 @@ mod tests {
 				start_sequence: Sequence {
 					actions: vec![Action {
 						predelay_ms: 100,
+						jitter_ms: None,
 						action_event: ActionEvent::None,
 					}],
 				},
@@ -533,9 +2103,15 @@ mod tests {
 				end_sequence: Sequence {
 					actions: vec![Action {
 						predelay_ms: 300,
+						jitter_ms: None,
 						action_event: ActionEvent::None,
 					}],
 				},
+				max_loops: None,
+				max_loop_ms: None,
+				trigger_mode: TriggerMode::Hold,
+				speed_multiplier: None,
+				cut_mode: StopMode::Graceful,
 				cut_channels: vec![Channel::new(1)],
 				id: MacroId::new(1),
 				name: "Name".to_string(),
@@ -543,16 +2119,16 @@ mod tests {
 			}],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
 		));
 
-		macro_state.tick(300, &mut vec![]);
+		macro_state.tick(&profile, 300, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -570,10 +2146,10 @@ mod tests {
 			)],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -581,7 +2157,7 @@ mod tests {
 
 		macro_state.stop();
 
-		macro_state.tick(200, &mut vec![]);
+		macro_state.tick(&profile, 200, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
@@ -599,10 +2175,10 @@ mod tests {
 			)],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Loop(_)
@@ -610,13 +2186,13 @@ mod tests {
 
 		macro_state.stop();
 
-		macro_state.tick(200, &mut vec![]);
+		macro_state.tick(&profile, 200, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
 		));
 
-		macro_state.tick(300, &mut vec![]);
+		macro_state.tick(&profile, 300, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::Finished
@@ -634,18 +2210,167 @@ mod tests {
 			)],
 		);
 
-		let key_state = KeyState::from(&device_key);
-		let mut macro_state = MacroState::from(&device_key.default_layer.macros[0], &key_state);
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
 
 		macro_state.stop();
 
-		macro_state.tick(100, &mut vec![]);
+		macro_state.tick(&profile, 100, &mut vec![]);
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn macro_goes_to_end_after_max_loops() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.max_loops = Some(2);
+		let device_key = new_test_device_key(KeyId::new(1), vec![macro_]);
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
+
+		macro_state.tick(&profile, 100, &mut vec![]);
+		macro_state.tick(&profile, 200, &mut vec![]);
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		macro_state.tick(&profile, 200, &mut vec![]);
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn macro_with_zero_max_loops_skips_loop() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.max_loops = Some(0);
+		let device_key = new_test_device_key(KeyId::new(1), vec![macro_]);
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
+
+		macro_state.tick(&profile, 100, &mut vec![]);
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn macro_goes_to_end_after_max_loop_duration() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.max_loop_ms = Some(500);
+		let device_key = new_test_device_key(KeyId::new(1), vec![macro_]);
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
+
+		macro_state.tick(&profile, 100, &mut vec![]);
+		macro_state.tick(&profile, 400, &mut vec![]);
+		assert!(matches!(
+			macro_state.current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		macro_state.tick(&profile, 200, &mut vec![]);
 		assert!(matches!(
 			macro_state.current_sequence,
 			CurrentSequence::End(_)
 		));
 	}
 
+	#[test]
+	fn empty_loop_ends_after_max_loop_duration() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![];
+		macro_.loop_sequence.actions = vec![];
+		macro_.end_sequence.actions = vec![new_test_action(
+			0,
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+		)];
+		macro_.max_loop_ms = Some(300);
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut macro_state = new_test_macro_state(&profile);
+
+		macro_state.tick(&profile, 0, &mut vec![]);
+		macro_state.tick(&profile, 200, &mut vec![]);
+		assert_eq!(macro_state.next_deadline_ms(&profile), Some(100));
+
+		let mut events = vec![];
+		macro_state.tick(&profile, 150, &mut events);
+		assert_eq!(
+			events.iter().map(|e| e.offset_ms).collect::<Vec<_>>(),
+			vec![100]
+		);
+		assert!(macro_state.is_finished());
+	}
+
+	#[test]
+	fn macro_carries_remaining_time_into_next_sequence_once() {
+		let device_key = new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		);
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
+		let mut events = vec![];
+
+		macro_state.tick(&profile, 499, &mut events);
+		assert_eq!(events.len(), 2);
+		assert_eq!(macro_state.loops_completed, 1);
+	}
+
+	#[test]
+	fn macro_abort_finishes_and_releases_held_input() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![
+			new_test_action(
+				100,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+			),
+			new_test_action(
+				0,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B)),
+			),
+			new_test_action(
+				0,
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+			),
+			new_test_action(
+				0,
+				ActionEvent::Mouse(MouseEvent::ButtonDown(MouseButton::Left)),
+			),
+		];
+		let device_key = new_test_device_key(KeyId::new(1), vec![macro_]);
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut macro_state = new_test_macro_state(&profile);
+
+		macro_state.tick(&profile, 150, &mut vec![]);
+		let releases = macro_state.abort();
+
+		assert!(macro_state.is_finished());
+		assert_eq!(releases.len(), 2);
+		assert!(matches!(
+			releases[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::B))
+		));
+		assert!(matches!(
+			releases[1],
+			ActionEvent::Mouse(MouseEvent::ButtonUp(MouseButton::Left))
+		));
+	}
+
 	// ------- KEYBOARD STATE TESTS --------
 
 	#[test]
@@ -660,202 +2385,1841 @@ mod tests {
 		)]);
 		let mut state = KeyboardState::from(&profile);
 
-		assert_eq!(state.macros.len(), 0);
+		assert_eq!(state.macros.len(), 0);
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+	}
+
+	#[test]
+	fn keyboard_tick_updates_macros() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Start(_)
+		));
+
+		state.tick(100, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		state.tick(200, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn releasing_a_key_stops_a_macro() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.release_key(KeyId::new(1));
+
+		state.tick(100, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn toggle_macro_ignores_release_and_stops_on_second_press() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.trigger_mode = TriggerMode::Toggle;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.release_key(KeyId::new(1));
+		assert!(state.macros[0].is_running());
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+		assert!(!state.macros[0].is_running());
+
+		state.release_key(KeyId::new(1));
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 2);
+		assert!(state.macros[1].is_running());
+	}
+
+	#[test]
+	fn on_release_macro_starts_on_release() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.trigger_mode = TriggerMode::OnRelease;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 0);
+
+		state.release_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+
+		state.tick(100, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn one_shot_macro_loops_once_regardless_of_release() {
+		let mut macro_ = new_test_macro(MacroId::new(1), Some(Channel::new(1)), vec![]);
+		macro_.trigger_mode = TriggerMode::OneShot;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.release_key(KeyId::new(1));
+
+		state.tick(100, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		state.press_key(KeyId::new(1));
+		state.tick(200, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn short_press_plays_macro_on_release() {
+		let profile = new_test_profile(vec![new_test_press_duration_key(KeyId::new(1))]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.release_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].id, MacroId::new(1));
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn long_press_plays_macro_while_held() {
+		let profile = new_test_profile(vec![new_test_press_duration_key(KeyId::new(1))]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(500, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.tick(300, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].id, MacroId::new(2));
+		assert!(state.macros[0].is_running());
+
+		state.release_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn press_between_thresholds_plays_nothing() {
+		let profile = new_test_profile(vec![new_test_press_duration_key(KeyId::new(1))]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(500, &mut vec![]);
+		state.release_key(KeyId::new(1));
+		state.tick(500, &mut vec![]);
+
+		assert_eq!(state.macros.len(), 0);
+	}
+
+	#[test]
+	fn seeded_keyboard_state_jitters_deterministically() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions[0].jitter_ms = Some(50);
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+
+		let delays = |seed: u32| -> Vec<u32> {
+			let mut state = KeyboardState::from(&profile);
+			state.seed_rng(seed);
+			(0..10)
+				.map(|_| {
+					state.press_key(KeyId::new(1));
+					match &state.macros.last().unwrap().current_sequence {
+						CurrentSequence::Start(seq) => seq.pending[0],
+						_ => panic!("macro should be in its start sequence"),
+					}
+				})
+				.collect()
+		};
+
+		assert_eq!(delays(1234), delays(1234));
+		assert_ne!(delays(1234), delays(4321));
+	}
+
+	#[test]
+	fn keys_share_library_macros() {
+		let mut key_1 = new_test_device_key(KeyId::new(1), vec![]);
+		key_1.default_layer.macros = vec![MacroRef::Library(MacroId::new(10))];
+		let mut key_2 = new_test_device_key(KeyId::new(2), vec![]);
+		key_2.default_layer.macros = vec![
+			MacroRef::Library(MacroId::new(10)),
+			MacroRef::Library(MacroId::new(11)),
+		];
+		let mut profile = new_test_profile(vec![key_1, key_2]);
+		profile.library = vec![new_test_macro(MacroId::new(10), None, vec![])];
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+
+		// the unknown reference is skipped
+		assert_eq!(state.macros.len(), 2);
+		assert_eq!(state.macros[0].loc, MacroLoc::Library(0));
+		assert_eq!(state.macros[1].loc, MacroLoc::Library(0));
+	}
+
+	#[test]
+	fn blocking_call_waits_for_called_macro() {
+		let mut caller = new_test_macro(MacroId::new(1), None, vec![]);
+		caller.start_sequence.actions = vec![
+			new_test_action(0, ActionEvent::Call(MacroId::new(10), CallMode::Blocking)),
+			new_test_action(50, ActionEvent::Mouse(MouseEvent::Move(0, 0))),
+		];
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![caller])]);
+		profile.library = vec![new_test_library_macro(MacroId::new(10))];
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		state.press_key(KeyId::new(1));
+		tick_all(&mut state, 100, &mut events);
+		tick_all(&mut state, 100, &mut events);
+		assert_eq!(events.len(), 3);
+
+		tick_all(&mut state, 50, &mut events);
+		assert_eq!(events.len(), 4);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Call(_, CallMode::Blocking)
+		));
+		assert!(matches!(
+			events[1],
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A))
+		));
+		assert!(matches!(
+			events[2],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+		assert!(matches!(
+			events[3],
+			ActionEvent::Mouse(MouseEvent::Move(0, 0))
+		));
+	}
+
+	#[test]
+	fn detached_call_runs_alongside_caller() {
+		let mut caller = new_test_macro(MacroId::new(1), None, vec![]);
+		caller.start_sequence.actions = vec![
+			new_test_action(0, ActionEvent::Call(MacroId::new(10), CallMode::Detached)),
+			new_test_action(50, ActionEvent::Mouse(MouseEvent::Move(0, 0))),
+		];
+		let mut library_macro = new_test_library_macro(MacroId::new(10));
+		library_macro.max_loops = None;
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![caller])]);
+		profile.library = vec![library_macro];
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		state.press_key(KeyId::new(1));
+		tick_all(&mut state, 100, &mut events);
+		assert_eq!(events.len(), 3);
+		assert_eq!(state.macros.len(), 2);
+		assert_eq!(state.macros[1].id, MacroId::new(10));
+
+		// the called macro inherits the key it was started from
+		state.release_key(KeyId::new(1));
+		assert!(!state.macros[0].is_running());
+		assert!(!state.macros[1].is_running());
+	}
+
+	#[test]
+	fn recursive_calls_stop_at_max_depth() {
+		let mut caller = new_test_macro(MacroId::new(1), None, vec![]);
+		caller.start_sequence.actions = vec![new_test_action(
+			0,
+			ActionEvent::Call(MacroId::new(10), CallMode::Blocking),
+		)];
+		let mut library_macro = new_test_library_macro(MacroId::new(10));
+		library_macro.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Call(MacroId::new(10), CallMode::Blocking),
+		)];
+		library_macro.end_sequence.actions = vec![];
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![caller])]);
+		profile.library = vec![library_macro];
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		state.press_key(KeyId::new(1));
+		tick_all(&mut state, 1000, &mut events);
+
+		let calls = events
+			.iter()
+			.filter(|e| matches!(e, ActionEvent::Call(_, _)))
+			.count();
+		assert_eq!(calls, MAX_CALL_DEPTH as usize + 1);
+	}
+
+	#[test]
+	fn pressing_a_key_cuts_own_channel() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 1);
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 2);
+
+		state.tick(100, &mut vec![]);
+
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn pressing_a_key_cuts_other_channel() {
+		let key_1 = KeyId::new(1);
+		let key_2 = KeyId::new(2);
+
+		let profile = new_test_profile(vec![
+			new_test_device_key(
+				key_1,
+				vec![new_test_macro(
+					MacroId::new(1),
+					Some(Channel::new(1)),
+					vec![],
+				)],
+			),
+			new_test_device_key(
+				key_2,
+				vec![new_test_macro(
+					MacroId::new(1),
+					Some(Channel::new(2)),
+					vec![Channel::new(1)],
+				)],
+			),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(key_1);
+		assert_eq!(state.macros.len(), 1);
+
+		state.press_key(key_2);
+		assert_eq!(state.macros.len(), 2);
+
+		state.tick(100, &mut vec![]);
+
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+		assert!(matches!(
+			state.macros[1].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn aborting_cut_releases_on_next_tick() {
+		let mut held = new_test_library_macro(MacroId::new(1));
+		held.play_channel = Some(Channel::new(1));
+		held.max_loops = None;
+		let mut cutter = new_test_macro(
+			MacroId::new(2),
+			Some(Channel::new(2)),
+			vec![Channel::new(1)],
+		);
+		cutter.cut_mode = StopMode::Abort;
+
+		let profile = new_test_profile(vec![
+			new_test_device_key(KeyId::new(1), vec![held]),
+			new_test_device_key(KeyId::new(2), vec![cutter]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+
+		state.press_key(KeyId::new(2));
+		assert!(state.macros[0].is_finished());
+
+		let mut events = vec![];
+		tick_all(&mut state, 1, &mut events);
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].id, MacroId::new(2));
+	}
+
+	#[test]
+	fn stop_all_aborts_and_releases_everything() {
+		let mut hold_a = new_test_library_macro(MacroId::new(1));
+		hold_a.max_loops = None;
+		let mut hold_b = new_test_library_macro(MacroId::new(2));
+		hold_b.max_loops = None;
+		hold_b.start_sequence.actions[0].action_event =
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B));
+
+		let profile = new_test_profile(vec![
+			new_test_device_key(KeyId::new(1), vec![hold_a]),
+			new_test_device_key(KeyId::new(2), vec![hold_b]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		state.tick(100, &mut vec![]);
+
+		state.stop_all(StopMode::Abort);
+		assert!(state.macros.iter().all(|m| m.is_finished()));
+
+		let mut events = vec![];
+		tick_all(&mut state, 1, &mut events);
+		assert_eq!(events.len(), 2);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+		assert!(matches!(
+			events[1],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::B))
+		));
+		assert_eq!(state.macros.len(), 0);
+	}
+
+	#[test]
+	fn stop_all_releases_keys_left_down_by_finished_macros() {
+		let mut press_a = new_test_library_macro(MacroId::new(1));
+		press_a.end_sequence.actions = vec![];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![press_a])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.stop_all(StopMode::Graceful);
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+
+		// nothing is left to release
+		state.stop_all(StopMode::Graceful);
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+		assert_eq!(events.len(), 0);
+	}
+
+	#[test]
+	fn graceful_stop_all_releases_held_keys() {
+		let mut hold_a = new_test_library_macro(MacroId::new(1));
+		hold_a.max_loops = None;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![hold_a])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+
+		state.stop_all(StopMode::Graceful);
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn stop_all_action_stops_other_macros() {
+		let mut hold_a = new_test_library_macro(MacroId::new(1));
+		hold_a.max_loops = None;
+		let mut panic = new_test_macro(MacroId::new(2), None, vec![]);
+		panic.start_sequence.actions =
+			vec![new_test_action(10, ActionEvent::StopAll(StopMode::Abort))];
+
+		let profile = new_test_profile(vec![
+			new_test_device_key(KeyId::new(1), vec![hold_a]),
+			new_test_device_key(KeyId::new(2), vec![panic]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+		state.press_key(KeyId::new(2));
+
+		let mut events = vec![];
+		tick_all(&mut state, 10, &mut events);
+		assert_eq!(events.len(), 2);
+		assert!(matches!(events[0], ActionEvent::StopAll(StopMode::Abort)));
+		assert!(matches!(
+			events[1],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+		assert_eq!(state.macros.len(), 0);
+	}
+
+	#[test]
+	fn stop_all_clears_internal_tags_when_configured() {
+		let mut profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		profile.stop_all_clears_tags = true;
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.stop_all(StopMode::Graceful);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn paused_macro_keeps_its_progress() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(50, &mut vec![]);
+
+		state.pause_macro(MacroId::new(1));
+		state.tick(1000, &mut vec![]);
+		match &state.macros[0].current_sequence {
+			CurrentSequence::Start(seq) => assert_eq!(seq.elapsed_ms, 50),
+			_ => panic!("paused macro should still be in its start sequence"),
+		}
+
+		state.resume_macro(MacroId::new(1));
+		state.tick(50, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn stopping_a_paused_macro_lets_it_finish() {
+		let mut hold_a = new_test_library_macro(MacroId::new(1));
+		hold_a.max_loops = None;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![hold_a])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+		state.pause_macro(MacroId::new(1));
+
+		state.stop_all(StopMode::Graceful);
+		assert!(!state.macros[0].paused);
+		for _ in 0..3 {
+			state.tick(100, &mut vec![]);
+		}
+
+		assert_eq!(state.macros.len(), 0);
+		assert_eq!(state.next_deadline_ms(), None);
+	}
+
+	#[test]
+	fn releasing_a_paused_macro_plays_its_end_sequence() {
+		let mut hold_a = new_test_library_macro(MacroId::new(1));
+		hold_a.max_loops = None;
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![hold_a])]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+		state.pause_macro(MacroId::new(1));
+		state.release_key(KeyId::new(1));
+
+		// pausing again once it is stopping has no effect
+		state.pause_macro(MacroId::new(1));
+		let mut events = vec![];
+		tick_all(&mut state, 100, &mut events);
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			events[0],
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))
+		));
+	}
+
+	#[test]
+	fn macros_can_pause_and_resume_other_macros() {
+		let mut pause = new_test_macro(MacroId::new(2), None, vec![]);
+		pause.start_sequence.actions =
+			vec![new_test_action(10, ActionEvent::Pause(MacroId::new(1)))];
+		let mut resume = new_test_macro(MacroId::new(3), None, vec![]);
+		resume.start_sequence.actions =
+			vec![new_test_action(10, ActionEvent::Resume(MacroId::new(1)))];
+
+		let profile = new_test_profile(vec![
+			new_test_device_key(
+				KeyId::new(1),
+				vec![new_test_macro(MacroId::new(1), None, vec![])],
+			),
+			new_test_device_key(KeyId::new(2), vec![pause]),
+			new_test_device_key(KeyId::new(3), vec![resume]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		state.tick(10, &mut vec![]);
+		assert!(state.macros[0].paused);
+
+		state.press_key(KeyId::new(3));
+		state.tick(10, &mut vec![]);
+		assert!(!state.macros[0].paused);
+	}
+
+	#[test]
+	fn queued_macro_waits_for_free_channel() {
+		let mut profile = new_test_channel_profile();
+		profile.channels = vec![ChannelConfig {
+			channel: Channel::new(1),
+			policy: ChannelPolicy::Queue,
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		assert_eq!(state.macros.len(), 2);
+		assert!(state.macros[1].queued);
+
+		state.tick(100, &mut vec![]);
+		state.release_key(KeyId::new(1));
+		state.tick(200, &mut vec![]);
+		assert!(state.macros[1].queued);
+
+		state.tick(300, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].source.key(), Some(KeyId::new(2)));
+		assert!(!state.macros[0].queued);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Start(_)
+		));
+	}
+
+	#[test]
+	fn ignore_new_drops_macro_while_channel_busy() {
+		let mut profile = new_test_channel_profile();
+		profile.channels = vec![ChannelConfig {
+			channel: Channel::new(1),
+			policy: ChannelPolicy::IgnoreNew,
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].source.key(), Some(KeyId::new(1)));
+	}
+
+	#[test]
+	fn max_concurrent_limits_macros_on_channel() {
+		let mut profile = new_test_channel_profile();
+		profile.channels = vec![ChannelConfig {
+			channel: Channel::new(1),
+			policy: ChannelPolicy::MaxConcurrent(2),
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.len(), 2);
+	}
+
+	#[test]
+	fn channel_policies_count_macros_started_together() {
+		for (policy, playing, queued) in [
+			(ChannelPolicy::MaxConcurrent(2), 2, 0),
+			(ChannelPolicy::IgnoreNew, 1, 0),
+			(ChannelPolicy::Queue, 1, 2),
+		] {
+			let mut profile = new_test_profile(vec![new_test_device_key(
+				KeyId::new(1),
+				(1..=3)
+					.map(|id| new_test_macro(MacroId::new(id), Some(Channel::new(1)), vec![]))
+					.collect(),
+			)]);
+			profile.channels = vec![ChannelConfig {
+				channel: Channel::new(1),
+				policy,
+			}];
+			let mut state = KeyboardState::from(&profile);
+
+			state.press_key(KeyId::new(1));
+			assert_eq!(state.macros.iter().filter(|m| !m.queued).count(), playing);
+			assert_eq!(state.macros.iter().filter(|m| m.queued).count(), queued);
+		}
+	}
+
+	#[test]
+	fn runtime_tags_leave_room_for_a_new_profile() {
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![])]);
+		let mut state = KeyboardState::from(&profile);
+		for index in 0..2 * MAX_TAGS {
+			state.set_external_tags(vec![LayerTag::new(index.to_string())]);
+		}
+		state.enable_notifications();
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert!(state
+			.drain_notifications()
+			.any(|n| n == Notification::TagAdded(LayerTag::new("test".to_string()))));
+
+		let new_profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		state.update_key_profile(&new_profile);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		// the tag stayed active while it got an id
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![Notification::LayerChanged {
+				key: KeyId::new(1),
+				layer: LayerId::new(2),
+			}]
+		);
+	}
+
+	#[test]
+	fn updating_profile_reports_layers_against_the_old_profile() {
+		let profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		state.enable_notifications();
+
+		// staying on the tagged layer is no change
+		let same_profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		state.update_key_profile(&same_profile);
+		assert_eq!(state.drain_notifications().count(), 0);
+
+		let untagged_profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![])]);
+		state.update_key_profile(&untagged_profile);
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![Notification::LayerChanged {
+				key: KeyId::new(1),
+				layer: LayerId::new(1),
+			}]
+		);
+	}
+
+	#[test]
+	fn updating_profile_releases_macros() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(1));
+
+		let new_profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(
+				MacroId::new(1),
+				Some(Channel::new(1)),
+				vec![Channel::new(1)],
+			)],
+		)]);
+		state.update_key_profile(&new_profile);
+
+		state.tick(100, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::End(_)
+		));
+	}
+
+	#[test]
+	fn old_profile_is_kept_until_its_macros_finish() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_library_macro(MacroId::new(1))],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+		state.tick(100, &mut vec![]);
+
+		let new_profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![])]);
+		state.update_key_profile(&new_profile);
+		assert_eq!(state.profiles.retired.len(), 1);
+
+		// the end sequence still plays from the old profile
+		let mut events = vec![];
+		tick_all(&mut state, 100, &mut events);
+		assert!(matches!(
+			events[..],
+			[ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A))]
+		));
+		assert!(state.macros.is_empty());
+		assert!(state.profiles.retired.is_empty());
+	}
+
+	#[test]
+	fn updating_profile_keeps_unchanged_macros() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+		state.tick(150, &mut vec![]);
+
+		let new_profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(state.macros[0].is_running());
+		assert_eq!(state.macros[0].generation, 1);
+
+		// the loop carries on where it was
+		let mut events = vec![];
+		tick_all(&mut state, 150, &mut events);
+		assert_eq!(events.len(), 1);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+	}
+
+	#[test]
+	fn updating_profile_stops_changed_macros() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+
+		let mut changed = new_test_macro(MacroId::new(1), None, vec![]);
+		changed.loop_sequence.actions[0].predelay_ms = 250;
+		let new_profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![changed])]);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(!state.macros[0].is_running());
+		assert_eq!(state.macros[0].generation, 0);
+	}
+
+	#[test]
+	fn updating_profile_stops_macros_of_removed_keys() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+
+		let new_profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(2),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn updating_profile_stops_macros_of_removed_layers() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+
+		let mut new_profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		new_profile.keys[0].default_layer.id = LayerId::new(3);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn updating_profile_stops_macros_with_other_ids() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+
+		let new_profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(2), None, vec![])],
+		)]);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn updating_profile_keeps_unchanged_called_macros() {
+		let profile = new_test_calling_profile(new_test_library_macro(MacroId::new(10)));
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+		state.tick(50, &mut vec![]);
+
+		let new_profile = new_test_calling_profile(new_test_library_macro(MacroId::new(10)));
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(state.macros[0].is_running());
+		let call = state.macros[0].call.as_ref().unwrap();
+		assert_eq!((call.loc, call.generation), (MacroLoc::Library(0), 1));
+	}
+
+	#[test]
+	fn updating_profile_stops_macros_whose_callee_changed() {
+		let profile = new_test_calling_profile(new_test_library_macro(MacroId::new(10)));
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+		state.tick(50, &mut vec![]);
+
+		let mut changed = new_test_library_macro(MacroId::new(10));
+		changed.end_sequence.actions[0].predelay_ms = 50;
+		let new_profile = new_test_calling_profile(changed);
+		state.update_key_profile_with(&new_profile, ProfileUpdate::KeepUnchangedMacros);
+
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn internal_tags_affect_macro_selection() {
+		let expected_macro_id = MacroId::new(2);
+		let other_macro_id = MacroId::new(1);
+
+		let device_key = DeviceKey {
+			key_id: KeyId::new(1),
+			layers: vec![TaggedDeviceKeyLayer {
+				layer: DeviceKeyLayer {
+					id: LayerId::new(2),
+					macros: vec![new_test_macro(
+						expected_macro_id,
+						Some(Channel::new(1)),
+						vec![Channel::new(1)],
+					)
+					.into()],
+					press_macros: vec![],
+				},
+				tags: vec![LayerTag::new("test".to_string())],
+				match_type: TagMatchType::All,
+				priority: 0,
+				mode: LayerMode::Opaque,
+			}],
+			default_layer: DeviceKeyLayer {
+				id: LayerId::new(1),
+				macros: vec![new_test_macro(
+					other_macro_id,
+					Some(Channel::new(1)),
+					vec![Channel::new(1)],
+				)
+				.into()],
+				press_macros: vec![],
+			},
+		};
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+
+		state.press_key(KeyId::new(1));
+
+		assert_eq!(state.macros[0].id, expected_macro_id);
+	}
+
+	#[test]
+	fn tag_expression_affects_macro_selection() {
+		let mut device_key = new_test_tagged_device_key(MacroId::new(2), MacroId::new(1));
+		device_key.layers[0].match_type =
+			TagMatchType::Expression(TagExpr::parse("fn & !shift").unwrap());
+
+		let profile = new_test_profile(vec![device_key]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("fn".to_string())]);
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros[0].id, MacroId::new(2));
+		state.release_key(KeyId::new(1));
+
+		state.set_external_tags(vec![LayerTag::new("shift".to_string())]);
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.macros.last().unwrap().id, MacroId::new(1));
+	}
+
+	#[test]
+	fn tag_set_by_two_macros_stays_until_both_clear_it() {
+		let profile = new_test_profile(vec![
+			new_test_tagged_device_key(MacroId::new(2), MacroId::new(1)),
+			new_test_device_key(KeyId::new(2), vec![new_test_tag_macro(MacroId::new(3))]),
+			new_test_device_key(KeyId::new(3), vec![new_test_tag_macro(MacroId::new(4))]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(2));
+		state.press_key(KeyId::new(3));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.release_key(KeyId::new(2));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.release_key(KeyId::new(3));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn idempotent_tag_clears_on_first_release() {
+		let mut profile = new_test_profile(vec![
+			new_test_tagged_device_key(MacroId::new(2), MacroId::new(1)),
+			new_test_device_key(KeyId::new(2), vec![new_test_tag_macro(MacroId::new(3))]),
+			new_test_device_key(KeyId::new(3), vec![new_test_tag_macro(MacroId::new(4))]),
+		]);
+		profile.internal_tag_mode = InternalTagMode::Idempotent;
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(2));
+		state.press_key(KeyId::new(3));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.release_key(KeyId::new(2));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn pushed_tags_switch_layers_until_popped() {
+		let mut push_macro = new_test_macro(MacroId::new(3), None, vec![]);
+		push_macro.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Push(
+				LayerTag::new("test".to_string()),
+				PushMode::Exclusive,
+			)),
+		)];
+		push_macro.loop_sequence.actions = vec![];
+		push_macro.trigger_mode = TriggerMode::OneShot;
+		let mut pop_macro = new_test_macro(MacroId::new(4), None, vec![]);
+		pop_macro.start_sequence.actions =
+			vec![new_test_action(10, ActionEvent::Layer(LayerEvent::Pop))];
+		pop_macro.loop_sequence.actions = vec![];
+		pop_macro.trigger_mode = TriggerMode::OneShot;
+
+		let profile = new_test_profile(vec![
+			new_test_tagged_device_key(MacroId::new(2), MacroId::new(1)),
+			new_test_device_key(KeyId::new(2), vec![push_macro]),
+			new_test_device_key(KeyId::new(3), vec![pop_macro]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.press_key(KeyId::new(2));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.press_key(KeyId::new(3));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn timed_tag_expires_after_duration() {
+		let profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tag_for(LayerTag::new("test".to_string()), 5000);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.tick(4999, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.tick(1, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+		assert_eq!(state.timed_tags.len(), 0);
+	}
+
+	#[test]
+	fn timed_tag_from_macro_keeps_other_holds() {
+		let mut timed_macro = new_test_macro(MacroId::new(3), None, vec![]);
+		timed_macro.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::SetFor(LayerTag::new("test".to_string()), 100)),
+		)];
+		timed_macro.loop_sequence.actions = vec![];
+		timed_macro.trigger_mode = TriggerMode::OneShot;
+
+		let profile = new_test_profile(vec![
+			new_test_tagged_device_key(MacroId::new(2), MacroId::new(1)),
+			new_test_device_key(KeyId::new(2), vec![timed_macro]),
+		]);
+		let mut state = KeyboardState::from(&profile);
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+
+		state.press_key(KeyId::new(2));
+		state.tick(10, &mut vec![]);
+		state.tick(100, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.remove_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn timed_tag_keeps_idempotent_holds() {
+		let mut profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		profile.internal_tag_mode = InternalTagMode::Idempotent;
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		state.add_internal_tag_for(LayerTag::new("test".to_string()), 100);
+		state.tick(100, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.add_internal_tag_for(LayerTag::new("test".to_string()), 100);
+		state.remove_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		state.tick(100, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+	}
+
+	#[test]
+	fn tag_changes_are_reported() {
+		let profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.enable_notifications();
+
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![
+				Notification::TagAdded(LayerTag::new("test".to_string())),
+				Notification::LayerChanged {
+					key: KeyId::new(1),
+					layer: LayerId::new(2),
+				},
+			]
+		);
+
+		// already active through the internal tag
+		state.set_external_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(state.drain_notifications().count(), 0);
+
+		state.remove_internal_tags(vec![LayerTag::new("test".to_string())]);
+		state.set_external_tags(vec![]);
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![
+				Notification::TagRemoved(LayerTag::new("test".to_string())),
+				Notification::LayerChanged {
+					key: KeyId::new(1),
+					layer: LayerId::new(1),
+				},
+			]
+		);
+	}
+
+	#[test]
+	fn tag_changes_from_macros_are_reported() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_tag_macro(MacroId::new(1))],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.enable_notifications();
+
+		state.press_key(KeyId::new(1));
+		state.tick(10, &mut vec![]);
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![Notification::TagAdded(LayerTag::new("test".to_string()))]
+		);
+
+		state.release_key(KeyId::new(1));
+		state.tick(10, &mut vec![]);
+		assert_eq!(
+			state.drain_notifications().collect::<Vec<_>>(),
+			vec![Notification::TagRemoved(LayerTag::new("test".to_string()))]
+		);
+	}
+
+	#[test]
+	fn notifications_are_off_until_enabled() {
+		let profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		assert_eq!(state.drain_notifications().count(), 0);
+		assert!(!state.take_notification_overflow());
+		assert!(state.is_tag_active(&LayerTag::new("test".to_string())));
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+	}
+
+	#[test]
+	fn full_notification_queue_reports_overflow() {
+		let profile = new_test_profile(vec![]);
+		let mut state = KeyboardState::from(&profile);
+		state.enable_notifications();
+
+		let tags = (0..=MAX_NOTIFICATIONS)
+			.map(|index| LayerTag::new(index.to_string()))
+			.collect();
+		state.add_internal_tags(tags);
+
+		assert_eq!(state.drain_notifications().count(), MAX_NOTIFICATIONS);
+		assert!(state.take_notification_overflow());
+		assert!(!state.take_notification_overflow());
+	}
+
+	#[test]
+	fn enter_trigger_plays_while_condition_matches() {
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![new_test_tag_trigger("game", TriggerEdge::Enter, 1)];
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("game".to_string())]);
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].source, MacroSource::Trigger(0));
+
+		state.tick(300, &mut vec![]);
+		assert!(matches!(
+			state.macros[0].current_sequence,
+			CurrentSequence::Loop(_)
+		));
+
+		state.remove_internal_tags(vec![LayerTag::new("game".to_string())]);
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn exit_trigger_plays_when_condition_stops_matching() {
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![new_test_tag_trigger("game", TriggerEdge::Exit, 1)];
+		let mut state = KeyboardState::from(&profile);
+
+		state.set_external_tags(vec![LayerTag::new("game".to_string())]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.set_external_tags(vec![]);
+		assert_eq!(state.macros.len(), 1);
+	}
+
+	#[test]
+	fn trigger_matching_at_load_does_not_fire() {
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![new_test_tag_trigger("!game", TriggerEdge::Enter, 1)];
+		let mut state = KeyboardState::from(&profile);
+
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+	}
+
+	#[test]
+	fn trigger_fires_on_every_host_edge() {
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![new_test_tag_trigger("game", TriggerEdge::Enter, 1)];
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("game".to_string())]);
+		state.remove_internal_tags(vec![LayerTag::new("game".to_string())]);
+		state.add_internal_tags(vec![LayerTag::new("game".to_string())]);
+
+		assert_eq!(state.macros.len(), 2);
+		assert!(!state.macros[0].is_running());
+		assert!(state.macros[1].is_running());
+	}
+
+	#[test]
+	fn trigger_macro_clearing_its_own_condition_is_stopped() {
+		// clears the tag that started it
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Clear(LayerTag::new("game".to_string()))),
+		)];
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![TagTrigger {
+			condition: TagExpr::parse("game").unwrap(),
+			edge: TriggerEdge::Enter,
+			macro_: macro_.into(),
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("game".to_string())]);
+		state.tick(10, &mut vec![]);
+
+		assert_eq!(state.macros.len(), 1);
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn triggers_undoing_each_other_stop_at_max_chain() {
+		let mut clear = new_test_macro(MacroId::new(1), None, vec![]);
+		clear.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Clear(LayerTag::new("game".to_string()))),
+		)];
+		let mut set = new_test_macro(MacroId::new(2), None, vec![]);
+		set.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Set(LayerTag::new("game".to_string()))),
+		)];
+		let mut profile = new_test_profile(vec![]);
+		profile.tag_triggers = vec![
+			TagTrigger {
+				condition: TagExpr::parse("game").unwrap(),
+				edge: TriggerEdge::Enter,
+				macro_: clear.into(),
+			},
+			TagTrigger {
+				condition: TagExpr::parse("game").unwrap(),
+				edge: TriggerEdge::Exit,
+				macro_: set.into(),
+			},
+		];
+		let mut state = KeyboardState::from(&profile);
+
+		state.add_internal_tags(vec![LayerTag::new("game".to_string())]);
+		let mut events: Vec<ActionEvent> = Vec::new();
+		for _ in 0..100 {
+			tick_all(&mut state, 10, &mut events);
+		}
+
+		let tag_changes = events
+			.iter()
+			.filter(|event| matches!(event, ActionEvent::Layer(_)))
+			.count();
+		assert_eq!(tag_changes, MAX_TRIGGER_CHAIN as usize);
+		assert_eq!(state.macros.len(), 0);
+		assert_eq!(state.next_deadline_ms(), None);
+	}
+
+	#[test]
+	fn startup_macro_plays_on_first_tick_after_load() {
+		let mut profile = new_test_profile(vec![]);
+		profile.startup_macro = Some(new_test_macro(MacroId::new(1), None, vec![]).into());
+		let mut state = KeyboardState::from(&profile);
+		assert_eq!(state.macros.len(), 0);
+
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.macros[0].source, MacroSource::Startup);
+		assert!(!state.macros[0].is_running());
+
+		state.tick(1000, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.update_key_profile(&profile);
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+	}
+
+	#[test]
+	fn idle_tags_switch_layers_until_next_press() {
+		let mut profile = new_test_profile(vec![new_test_tagged_device_key(
+			MacroId::new(2),
+			MacroId::new(1),
+		)]);
+		profile.idle = vec![IdleAction {
+			timeout_ms: 1000,
+			macro_: None,
+			tags: vec![LayerTag::new("test".to_string())],
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.tick(999, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+
+		state.tick(1, &mut vec![]);
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(2)));
+
+		// the waking press plays from the layer that was active before idling
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.current_layer(KeyId::new(1)), Some(LayerId::new(1)));
+		assert_eq!(state.macros[0].id, MacroId::new(1));
+	}
+
+	#[test]
+	fn idle_macro_is_released_by_next_press() {
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![])]);
+		profile.idle = vec![IdleAction {
+			timeout_ms: 1000,
+			macro_: Some(new_test_macro(MacroId::new(1), None, vec![]).into()),
+			tags: vec![],
+		}];
+		let mut state = KeyboardState::from(&profile);
+
+		state.tick(500, &mut vec![]);
+		state.press_key(KeyId::new(1));
+		state.tick(999, &mut vec![]);
+		assert_eq!(state.macros.len(), 0);
+
+		state.tick(1, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+		assert!(state.macros[0].is_running());
+
+		// fires once per idle period
+		state.tick(5000, &mut vec![]);
+		assert_eq!(state.macros.len(), 1);
+
+		state.press_key(KeyId::new(1));
+		assert!(!state.macros[0].is_running());
+	}
+
+	#[test]
+	fn deadline_follows_next_action() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_macro(MacroId::new(1), None, vec![])],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		assert_eq!(state.next_deadline_ms(), Some(0));
+
+		state.tick(0, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), None);
+
+		state.press_key(KeyId::new(1));
+		assert_eq!(state.next_deadline_ms(), Some(100));
+
+		state.tick(40, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), Some(60));
+
+		let mut events = vec![];
+		tick_all(&mut state, 60, &mut events);
+		assert_eq!(events.len(), 1);
+		assert_eq!(state.next_deadline_ms(), Some(200));
+	}
+
+	#[test]
+	fn deadline_waits_for_release_on_empty_loop() {
+		let profile = new_test_profile(vec![new_test_device_key(
+			KeyId::new(1),
+			vec![new_test_tag_macro(MacroId::new(1))],
+		)]);
+		let mut state = KeyboardState::from(&profile);
+		state.press_key(KeyId::new(1));
+		state.tick(10, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), None);
+
+		state.release_key(KeyId::new(1));
+		assert_eq!(state.next_deadline_ms(), Some(0));
+
+		// the empty loop is left right away
+		state.tick(0, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), Some(10));
+	}
+
+	#[test]
+	fn zero_tick_plays_actions_due_right_away() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![new_test_action(
+			0,
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+		)];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+		state.tick(0, &mut vec![]);
+
 		state.press_key(KeyId::new(1));
-		assert_eq!(state.macros.len(), 1);
+		assert_eq!(state.next_deadline_ms(), Some(0));
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+
+		assert!(matches!(
+			events[..],
+			[ActionEvent::Keyboard(KeyboardEvent::KeyDown(
+				KeyboardKey::A
+			))]
+		));
+		assert_eq!(state.next_deadline_ms(), Some(200));
 	}
 
 	#[test]
-	fn keyboard_tick_updates_macros() {
-		let profile = new_test_profile(vec![new_test_device_key(
-			KeyId::new(1),
-			vec![new_test_macro(
-				MacroId::new(1),
-				Some(Channel::new(1)),
-				vec![Channel::new(1)],
-			)],
-		)]);
+	fn zero_delay_loop_plays_one_pass_per_tick() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![];
+		macro_.loop_sequence.actions = vec![
+			new_test_action(
+				0,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+			),
+			new_test_action(
+				0,
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+			),
+		];
+		macro_.end_sequence.actions = vec![];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
 		let mut state = KeyboardState::from(&profile);
+		state.tick(0, &mut vec![]);
 
 		state.press_key(KeyId::new(1));
-		assert_eq!(state.macros.len(), 1);
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::Start(_)
-		));
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+		assert_eq!(events.len(), 2);
+		assert_eq!(state.next_deadline_ms(), Some(0));
 
-		state.tick(100, &mut vec![]);
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::Loop(_)
-		));
+		// the pass that already started plays out before the macro ends
+		state.release_key(KeyId::new(1));
+		let mut events = vec![];
+		tick_all(&mut state, 0, &mut events);
+		assert_eq!(events.len(), 2);
+		assert!(state.macros.is_empty());
+		assert_eq!(state.next_deadline_ms(), None);
+	}
 
-		state.tick(200, &mut vec![]);
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::Loop(_)
-		));
+	#[test]
+	fn deadline_includes_held_presses_timed_tags_and_idle() {
+		let mut profile = new_test_profile(vec![new_test_press_duration_key(KeyId::new(1))]);
+		profile.idle = vec![IdleAction {
+			timeout_ms: 2000,
+			macro_: None,
+			tags: vec![],
+		}];
+		let mut state = KeyboardState::from(&profile);
+		state.tick(0, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), Some(2000));
+
+		state.add_internal_tag_for(LayerTag::new("test".to_string()), 1000);
+		assert_eq!(state.next_deadline_ms(), Some(1000));
+
+		state.press_key(KeyId::new(1));
+		state.tick(300, &mut vec![]);
+		assert_eq!(state.next_deadline_ms(), Some(500));
 	}
 
 	#[test]
-	fn releasing_a_key_stops_a_macro() {
+	fn timestamps_carry_sub_millisecond_time() {
 		let profile = new_test_profile(vec![new_test_device_key(
 			KeyId::new(1),
-			vec![new_test_macro(
-				MacroId::new(1),
-				Some(Channel::new(1)),
-				vec![Channel::new(1)],
-			)],
+			vec![new_test_library_macro(MacroId::new(1))],
 		)]);
 		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
 
-		state.press_key(KeyId::new(1));
-		state.release_key(KeyId::new(1));
+		state.press_key_at(KeyId::new(1), 1_000_000, &mut events);
+		for step in 1..400 {
+			state.tick_at(1_000_000 + step * 250, &mut events);
+		}
+		assert_eq!(events.len(), 0);
 
-		state.tick(100, &mut vec![]);
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::End(_)
-		));
+		state.tick_at(1_100_000, &mut events);
+		assert_eq!(events.len(), 1);
 	}
 
 	#[test]
-	fn pressing_a_key_cuts_own_channel() {
+	fn timestamped_press_starts_macro_at_press_time() {
 		let profile = new_test_profile(vec![new_test_device_key(
 			KeyId::new(1),
-			vec![new_test_macro(
-				MacroId::new(1),
-				Some(Channel::new(1)),
-				vec![Channel::new(1)],
-			)],
+			vec![new_test_library_macro(MacroId::new(1))],
 		)]);
 		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
 
-		state.press_key(KeyId::new(1));
-		assert_eq!(state.macros.len(), 1);
+		state.tick_at(0, &mut events);
+		state.press_key_at(KeyId::new(1), 50_000, &mut events);
+		state.tick_at(149_999, &mut events);
+		assert_eq!(events.len(), 0);
 
-		state.press_key(KeyId::new(1));
-		assert_eq!(state.macros.len(), 2);
+		state.tick_at(150_000, &mut events);
+		assert_eq!(events.len(), 1);
+	}
 
-		state.tick(100, &mut vec![]);
+	#[test]
+	fn timestamped_tick_resolves_held_press_on_time() {
+		let mut device_key = new_test_press_duration_key(KeyId::new(1));
+		device_key.default_layer.press_macros[1].macro_ =
+			new_test_library_macro(MacroId::new(2)).into();
+		let profile = new_test_profile(vec![device_key]);
+		let mut state = KeyboardState::from(&profile);
+		let mut events = vec![];
+
+		// held for 800ms, then the macro presses A 100ms later
+		state.press_key_at(KeyId::new(1), 0, &mut events);
+		state.tick_at(900_000, &mut events);
 
+		assert_eq!(events.len(), 1);
 		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::End(_)
+			events[0],
+			OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A))
 		));
 	}
 
 	#[test]
-	fn pressing_a_key_cuts_other_channel() {
-		let key_1 = KeyId::new(1);
-		let key_2 = KeyId::new(2);
-
-		let profile = new_test_profile(vec![
-			new_test_device_key(
-				key_1,
-				vec![new_test_macro(
-					MacroId::new(1),
-					Some(Channel::new(1)),
-					vec![],
-				)],
+	fn timestamped_tick_offsets_are_from_the_start_of_the_window() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![
+			new_test_action(
+				10,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
 			),
-			new_test_device_key(
-				key_2,
-				vec![new_test_macro(
-					MacroId::new(1),
-					Some(Channel::new(2)),
-					vec![Channel::new(1)],
-				)],
+			new_test_action(
+				30,
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
 			),
-		]);
+		];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
 		let mut state = KeyboardState::from(&profile);
+		let mut events: heapless::Deque<TimedOutput, 4> = heapless::Deque::new();
 
-		state.press_key(key_1);
-		assert_eq!(state.macros.len(), 1);
+		state.press_key_at(KeyId::new(1), 0, &mut events);
+		state.tick_at(50_000, &mut events);
 
-		state.press_key(key_2);
-		assert_eq!(state.macros.len(), 2);
+		assert_eq!(
+			events.iter().map(|e| e.offset_ms).collect::<Vec<_>>(),
+			vec![10, 40]
+		);
+	}
 
-		state.tick(100, &mut vec![]);
+	#[test]
+	fn timed_events_carry_offsets_and_sources() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![
+			new_test_action(
+				10,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+			),
+			new_test_action(
+				15,
+				ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+			),
+			new_test_action(
+				20,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B)),
+			),
+		];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+		state.tick(0, &mut vec![]);
+		state.press_key(KeyId::new(1));
 
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::End(_)
-		));
-		assert!(matches!(
-			state.macros[1].current_sequence,
-			CurrentSequence::Loop(_)
-		));
+		let mut events = vec![];
+		state.tick_timed(5, &mut events);
+		state.tick_timed(50, &mut events);
+
+		assert_eq!(
+			events.iter().map(|e| e.offset_ms).collect::<Vec<_>>(),
+			vec![5, 20, 40]
+		);
+		assert!(events
+			.iter()
+			.all(|e| e.macro_id == Some(MacroId::new(1)) && e.key_id == Some(KeyId::new(1))));
 	}
 
 	#[test]
-	fn updating_profile_releases_macros() {
-		let profile = new_test_profile(vec![new_test_device_key(
-			KeyId::new(1),
-			vec![new_test_macro(
-				MacroId::new(1),
-				Some(Channel::new(1)),
-				vec![Channel::new(1)],
-			)],
-		)]);
+	fn timed_events_from_called_macros_keep_their_macro() {
+		let mut caller = new_test_macro(MacroId::new(1), None, vec![]);
+		caller.start_sequence.actions = vec![
+			new_test_action(50, ActionEvent::Call(MacroId::new(10), CallMode::Blocking)),
+			new_test_action(
+				50,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B)),
+			),
+		];
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![caller])]);
+		profile.library = vec![new_test_library_macro(MacroId::new(10))];
 		let mut state = KeyboardState::from(&profile);
-
+		state.tick(0, &mut vec![]);
 		state.press_key(KeyId::new(1));
 
-		let new_profile = new_test_profile(vec![new_test_device_key(
-			KeyId::new(1),
-			vec![new_test_macro(
-				MacroId::new(1),
-				Some(Channel::new(1)),
-				vec![Channel::new(1)],
-			)],
-		)]);
-		state.update_key_profile(&new_profile);
-
-		state.tick(100, &mut vec![]);
-		assert!(matches!(
-			state.macros[0].current_sequence,
-			CurrentSequence::End(_)
-		));
+		let mut events = vec![];
+		state.tick_timed(400, &mut events);
+
+		let sources: Vec<_> = events
+			.iter()
+			.map(|e| (e.offset_ms, e.macro_id.unwrap()))
+			.collect();
+		assert_eq!(
+			sources,
+			vec![
+				(50, MacroId::new(1)),
+				(150, MacroId::new(10)),
+				(250, MacroId::new(10)),
+				(300, MacroId::new(1)),
+			]
+		);
 	}
 
 	#[test]
-	fn internal_tags_affect_macro_selection() {
-		let expected_macro_id = MacroId::new(2);
-		let other_macro_id = MacroId::new(1);
+	fn sink_gets_only_keyboard_and_mouse_events() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions = vec![
+			new_test_action(
+				10,
+				ActionEvent::Layer(LayerEvent::Set(LayerTag::new("test".to_string()))),
+			),
+			new_test_action(
+				10,
+				ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+			),
+		];
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+		let mut events: heapless::Deque<TimedOutput, 4> = heapless::Deque::new();
 
-		let device_key = DeviceKey {
-			key_id: KeyId::new(1),
-			layers: vec![TaggedDeviceKeyLayer {
-				layer: DeviceKeyLayer {
-					id: LayerId::new(2),
-					macros: vec![new_test_macro(
-						expected_macro_id,
-						Some(Channel::new(1)),
-						vec![Channel::new(1)],
-					)],
-				},
-				tags: vec![LayerTag::new("test".to_string())],
-				match_type: TagMatchType::All,
-			}],
-			default_layer: DeviceKeyLayer {
-				id: LayerId::new(1),
-				macros: vec![new_test_macro(
-					other_macro_id,
-					Some(Channel::new(1)),
-					vec![Channel::new(1)],
-				)],
-			},
-		};
+		state.press_key(KeyId::new(1));
+		state.tick(30, &mut events);
+
+		assert_eq!(
+			events.iter().copied().collect::<Vec<_>>(),
+			vec![TimedOutput {
+				event: OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+				offset_ms: 20,
+				macro_id: Some(MacroId::new(1)),
+				key_id: Some(KeyId::new(1)),
+			}]
+		);
+	}
 
-		let profile = new_test_profile(vec![device_key]);
+	#[test]
+	fn sink_gets_events_in_time_order() {
+		let mut first = new_test_macro(MacroId::new(1), None, vec![]);
+		first.start_sequence.actions = vec![new_test_action(
+			40,
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+		)];
+		let mut second = new_test_macro(MacroId::new(2), None, vec![]);
+		second.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B)),
+		)];
+		let profile = new_test_profile(vec![
+			new_test_device_key(KeyId::new(1), vec![first]),
+			new_test_device_key(KeyId::new(2), vec![second]),
+		]);
 		let mut state = KeyboardState::from(&profile);
+		let mut events: heapless::Deque<TimedOutput, 4> = heapless::Deque::new();
 
-		state.add_internal_tags(vec![LayerTag::new("test".to_string())]);
+		state.tick(0, &mut events);
+		state.press_key(KeyId::new(1));
+		state.press_key(KeyId::new(2));
+		state.tick(50, &mut events);
+
+		assert_eq!(
+			events
+				.iter()
+				.map(|e| (e.event, e.offset_ms))
+				.collect::<Vec<_>>(),
+			vec![
+				(
+					OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::B)),
+					10
+				),
+				(
+					OutputEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+					40
+				),
+			]
+		);
+	}
+
+	#[test]
+	fn trace_sink_places_events_across_ticks() {
+		let mut macro_ = new_test_macro(MacroId::new(1), None, vec![]);
+		macro_.start_sequence.actions[0].action_event =
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A));
+		macro_.loop_sequence.actions[0].action_event =
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A));
+		let profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![macro_])]);
+		let mut state = KeyboardState::from(&profile);
+		let mut trace = TraceSink::new();
 
+		state.tick(0, &mut trace);
 		state.press_key(KeyId::new(1));
+		state.tick(70, &mut trace);
+		state.tick(270, &mut trace);
 
-		assert_eq!(state.macros[0].macro_.id, expected_macro_id);
+		assert_eq!(
+			trace.records.iter().map(|r| r.at_ms).collect::<Vec<_>>(),
+			vec![100, 300]
+		);
 	}
 
 	#[test]
@@ -872,10 +4236,14 @@ mod tests {
 						expected_macro_id,
 						Some(Channel::new(1)),
 						vec![Channel::new(1)],
-					)],
+					)
+					.into()],
+					press_macros: vec![],
 				},
 				tags: vec![LayerTag::new("test".to_string())],
 				match_type: TagMatchType::All,
+				priority: 0,
+				mode: LayerMode::Opaque,
 			}],
 			default_layer: DeviceKeyLayer {
 				id: LayerId::new(1),
@@ -883,7 +4251,9 @@ mod tests {
 					other_macro_id,
 					Some(Channel::new(1)),
 					vec![Channel::new(1)],
-				)],
+				)
+				.into()],
+				press_macros: vec![],
 			},
 		};
 
@@ -894,7 +4264,7 @@ mod tests {
 
 		state.press_key(KeyId::new(1));
 
-		assert_eq!(state.macros[0].macro_.id, expected_macro_id);
+		assert_eq!(state.macros[0].id, expected_macro_id);
 	}
 
 	#[test]
@@ -911,10 +4281,14 @@ mod tests {
 						other_macro_id,
 						Some(Channel::new(1)),
 						vec![Channel::new(1)],
-					)],
+					)
+					.into()],
+					press_macros: vec![],
 				},
 				tags: vec![LayerTag::new("test".to_string())],
 				match_type: TagMatchType::All,
+				priority: 0,
+				mode: LayerMode::Opaque,
 			}],
 			default_layer: DeviceKeyLayer {
 				id: LayerId::new(1),
@@ -922,7 +4296,9 @@ mod tests {
 					expected_macro_id,
 					Some(Channel::new(1)),
 					vec![Channel::new(1)],
-				)],
+				)
+				.into()],
+				press_macros: vec![],
 			},
 		};
 
@@ -931,13 +4307,78 @@ mod tests {
 
 		state.press_key(KeyId::new(1));
 
-		assert_eq!(state.macros[0].macro_.id, expected_macro_id);
+		assert_eq!(state.macros[0].id, expected_macro_id);
 	}
 
 	// ------- HELPERS --------
 
+	// every event of the tick, including the ones a sink doesn't get
+	fn tick_all(
+		state: &mut KeyboardState<&KeyboardProfile>,
+		elapsed_ms: u32,
+		events: &mut Vec<ActionEvent>,
+	) {
+		let mut timed = Vec::new();
+		state.tick_timed(elapsed_ms, &mut timed);
+		events.extend(timed.into_iter().map(|timed| timed.event));
+	}
+
 	fn new_test_profile(keys: Vec<DeviceKey>) -> KeyboardProfile {
-		KeyboardProfile { keys }
+		KeyboardProfile {
+			keys,
+			library: Vec::new(),
+			channels: Vec::new(),
+			stop_all_clears_tags: false,
+			internal_tag_mode: InternalTagMode::Counted,
+			tag_triggers: Vec::new(),
+			startup_macro: None,
+			idle: Vec::new(),
+		}
+	}
+
+	// uses layer 2 while "test" is set and layer 1 otherwise
+	fn new_test_tagged_device_key(tagged_macro: MacroId, default_macro: MacroId) -> DeviceKey {
+		DeviceKey {
+			key_id: KeyId::new(1),
+			layers: vec![TaggedDeviceKeyLayer {
+				layer: DeviceKeyLayer {
+					id: LayerId::new(2),
+					macros: vec![new_test_macro(tagged_macro, Some(Channel::new(1)), vec![]).into()],
+					press_macros: vec![],
+				},
+				tags: vec![LayerTag::new("test".to_string())],
+				match_type: TagMatchType::All,
+				priority: 0,
+				mode: LayerMode::Opaque,
+			}],
+			default_layer: DeviceKeyLayer {
+				id: LayerId::new(1),
+				macros: vec![new_test_macro(default_macro, Some(Channel::new(1)), vec![]).into()],
+				press_macros: vec![],
+			},
+		}
+	}
+
+	// two keys playing on channel 1 without cutting it
+	fn new_test_channel_profile() -> KeyboardProfile {
+		new_test_profile(vec![
+			new_test_device_key(
+				KeyId::new(1),
+				vec![new_test_macro(
+					MacroId::new(1),
+					Some(Channel::new(1)),
+					vec![],
+				)],
+			),
+			new_test_device_key(
+				KeyId::new(2),
+				vec![new_test_macro(
+					MacroId::new(2),
+					Some(Channel::new(1)),
+					vec![],
+				)],
+			),
+		])
 	}
 
 	fn new_test_device_key(id: KeyId, macros: Vec<Macro>) -> DeviceKey {
@@ -946,31 +4387,130 @@ mod tests {
 			layers: Vec::new(),
 			default_layer: DeviceKeyLayer {
 				id: LayerId::new(1),
-				macros,
+				macros: macros.into_iter().map(MacroRef::from).collect(),
+				press_macros: Vec::new(),
+			},
+		}
+	}
+
+	fn new_test_press_duration_key(id: KeyId) -> DeviceKey {
+		let mut device_key = new_test_device_key(id, vec![]);
+		device_key.default_layer.press_macros = vec![
+			PressDurationMacro {
+				duration: PressDuration::ReleasedWithin(300),
+				macro_: new_test_macro(MacroId::new(1), None, vec![]).into(),
+			},
+			PressDurationMacro {
+				duration: PressDuration::HeldFor(800),
+				macro_: new_test_macro(MacroId::new(2), None, vec![]).into(),
 			},
+		];
+		device_key
+	}
+
+	// the first macro of the first key, as if the key was pressed
+	fn new_test_macro_state(profile: &KeyboardProfile) -> MacroState {
+		let loc = MacroLoc::Key(0, 0, 0);
+		let source = MacroSource::Key {
+			key: profile.keys[0].key_id,
+			layer: profile.keys[0].default_layer.id,
+		};
+		MacroState::new(
+			loc,
+			loc.resolve(profile).unwrap(),
+			source,
+			0,
+			Rng::default(),
+		)
+	}
+
+	fn new_test_action(predelay_ms: u32, action_event: ActionEvent) -> Action {
+		Action {
+			predelay_ms,
+			jitter_ms: None,
+			action_event,
+		}
+	}
+
+	// sets the "test" tag while held and clears it on release
+	fn new_test_tag_macro(id: MacroId) -> Macro {
+		let mut macro_ = new_test_macro(id, None, vec![]);
+		macro_.start_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Set(LayerTag::new("test".to_string()))),
+		)];
+		macro_.loop_sequence.actions = vec![];
+		macro_.end_sequence.actions = vec![new_test_action(
+			10,
+			ActionEvent::Layer(LayerEvent::Clear(LayerTag::new("test".to_string()))),
+		)];
+		macro_
+	}
+
+	// key 1 calls the library macro right away and waits for it
+	fn new_test_calling_profile(library_macro: Macro) -> KeyboardProfile {
+		let mut caller = new_test_macro(MacroId::new(1), None, vec![]);
+		caller.start_sequence.actions = vec![new_test_action(
+			0,
+			ActionEvent::Call(library_macro.id, CallMode::Blocking),
+		)];
+		let mut profile = new_test_profile(vec![new_test_device_key(KeyId::new(1), vec![caller])]);
+		profile.library = vec![library_macro];
+		profile
+	}
+
+	fn new_test_tag_trigger(condition: &str, edge: TriggerEdge, id: i128) -> TagTrigger {
+		TagTrigger {
+			condition: TagExpr::parse(condition).unwrap(),
+			edge,
+			macro_: new_test_macro(MacroId::new(id), None, vec![]).into(),
 		}
 	}
 
+	// presses and releases A, then finishes on its own
+	fn new_test_library_macro(id: MacroId) -> Macro {
+		let mut macro_ = new_test_macro(id, None, vec![]);
+		macro_.start_sequence.actions = vec![new_test_action(
+			100,
+			ActionEvent::Keyboard(KeyboardEvent::KeyDown(KeyboardKey::A)),
+		)];
+		macro_.loop_sequence.actions = vec![];
+		macro_.end_sequence.actions = vec![new_test_action(
+			100,
+			ActionEvent::Keyboard(KeyboardEvent::KeyUp(KeyboardKey::A)),
+		)];
+		macro_.max_loops = Some(0);
+		macro_
+	}
+
 	fn new_test_macro(id: MacroId, channel: Option<Channel>, cut: Vec<Channel>) -> Macro {
 		Macro {
 			start_sequence: Sequence {
 				actions: vec![Action {
 					predelay_ms: 100,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				}],
 			},
 			loop_sequence: Sequence {
 				actions: vec![Action {
 					predelay_ms: 200,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				}],
 			},
 			end_sequence: Sequence {
 				actions: vec![Action {
 					predelay_ms: 300,
+					jitter_ms: None,
 					action_event: ActionEvent::None,
 				}],
 			},
+			max_loops: None,
+			max_loop_ms: None,
+			trigger_mode: TriggerMode::Hold,
+			speed_multiplier: None,
+			cut_mode: StopMode::Graceful,
 			cut_channels: cut,
 			id,
 			name: "Name".to_string(),
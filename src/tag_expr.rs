@@ -0,0 +1,508 @@
+extern crate alloc;
+
+use alloc::boxed::Box;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::fmt;
+use core::str::FromStr;
+
+use serde::de::{self, Deserialize, Deserializer, Visitor};
+use serde::ser::{Serialize, Serializer};
+
+use crate::profile::LayerTag;
+use crate::{TagCondition, TagList};
+
+// deep enough for any real condition, shallow enough for the stack of a small MCU
+const MAX_NESTING: u8 = 32;
+
+// written as e.g. `(game | vim) & caps & !shift`, `!` binds tighter than `&`, which binds
+// tighter than `|`. An And without operands is written `(&)` and always holds, an Or without
+// operands is written `(|)` and never does. A tag with whitespace or any of `&|!()` in it is
+// quoted, as in `'caps lock' & fn`, with a quote inside doubled: `'it''s'`
+#[derive(Debug, PartialEq)]
+pub enum TagExpr {
+	Tag(LayerTag),
+	Not(Box<TagExpr>),
+	And(Vec<TagExpr>),
+	Or(Vec<TagExpr>),
+}
+
+impl TagExpr {
+	pub fn evaluate(&self, tags: &TagList) -> bool {
+		match self {
+			TagExpr::Tag(tag) => tags.contains(tag),
+			TagExpr::Not(expr) => !expr.evaluate(tags),
+			TagExpr::And(exprs) => exprs.iter().all(|expr| expr.evaluate(tags)),
+			TagExpr::Or(exprs) => exprs.iter().any(|expr| expr.evaluate(tags)),
+		}
+	}
+
+	pub fn compile(&self, tags: &mut TagList) -> TagCondition {
+		match self {
+			TagExpr::Tag(tag) => match tags.intern(tag) {
+				Some(id) => TagCondition::Tag(id),
+				// a tag the list has no room for never matches
+				None => TagCondition::Or(Vec::new()),
+			},
+			TagExpr::Not(expr) => TagCondition::Not(Box::new(expr.compile(tags))),
+			TagExpr::And(exprs) => {
+				TagCondition::And(exprs.iter().map(|expr| expr.compile(tags)).collect())
+			}
+			TagExpr::Or(exprs) => {
+				TagCondition::Or(exprs.iter().map(|expr| expr.compile(tags)).collect())
+			}
+		}
+	}
+
+	pub fn parse(input: &str) -> Result<TagExpr, TagExprError> {
+		let mut parser = Parser {
+			input,
+			position: 0,
+			depth: 0,
+		};
+		let expr = parser.parse_or()?;
+
+		parser.skip_whitespace();
+		match parser.peek() {
+			None => Ok(expr),
+			Some(_) => Err(TagExprError::UnexpectedChar(parser.position)),
+		}
+	}
+
+	fn fmt_operand(&self, f: &mut fmt::Formatter, parent_binds_tighter: bool) -> fmt::Result {
+		let empty = matches!(self, TagExpr::And(exprs) | TagExpr::Or(exprs) if exprs.is_empty());
+
+		if parent_binds_tighter && !empty {
+			write!(f, "({})", self)
+		} else {
+			write!(f, "{}", self)
+		}
+	}
+}
+
+impl fmt::Display for TagExpr {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TagExpr::Tag(tag) => fmt_tag(f, tag.as_str()),
+			TagExpr::Not(expr) => {
+				write!(f, "!")?;
+				expr.fmt_operand(f, matches!(**expr, TagExpr::And(_) | TagExpr::Or(_)))
+			}
+			TagExpr::And(exprs) if exprs.is_empty() => write!(f, "(&)"),
+			TagExpr::Or(exprs) if exprs.is_empty() => write!(f, "(|)"),
+			TagExpr::And(exprs) => {
+				for (index, expr) in exprs.iter().enumerate() {
+					if index > 0 {
+						write!(f, " & ")?;
+					}
+					expr.fmt_operand(f, matches!(expr, TagExpr::Or(_) | TagExpr::And(_)))?;
+				}
+				Ok(())
+			}
+			TagExpr::Or(exprs) => {
+				for (index, expr) in exprs.iter().enumerate() {
+					if index > 0 {
+						write!(f, " | ")?;
+					}
+					expr.fmt_operand(f, matches!(expr, TagExpr::Or(_)))?;
+				}
+				Ok(())
+			}
+		}
+	}
+}
+
+impl FromStr for TagExpr {
+	type Err = TagExprError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		TagExpr::parse(s)
+	}
+}
+
+// serialized in its string syntax
+impl Serialize for TagExpr {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		serializer.serialize_str(&self.to_string())
+	}
+}
+
+impl<'de> Deserialize<'de> for TagExpr {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		deserializer.deserialize_str(TagExprVisitor)
+	}
+}
+
+struct TagExprVisitor;
+
+impl<'de> Visitor<'de> for TagExprVisitor {
+	type Value = TagExpr;
+
+	fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		write!(f, "a tag expression")
+	}
+
+	fn visit_str<E: de::Error>(self, value: &str) -> Result<TagExpr, E> {
+		TagExpr::parse(value).map_err(E::custom)
+	}
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TagExprError {
+	UnexpectedEnd,
+	UnexpectedChar(usize),
+	// more than MAX_NESTING levels of parentheses and negations, at the given position
+	TooDeep(usize),
+}
+
+impl fmt::Display for TagExprError {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			TagExprError::UnexpectedEnd => write!(f, "unexpected end of tag expression"),
+			TagExprError::UnexpectedChar(position) => {
+				write!(f, "unexpected character at {} in tag expression", position)
+			}
+			TagExprError::TooDeep(position) => {
+				write!(f, "tag expression nested too deeply at {}", position)
+			}
+		}
+	}
+}
+
+struct Parser<'a> {
+	input: &'a str,
+	position: usize,
+	depth: u8,
+}
+
+impl<'a> Parser<'a> {
+	fn parse_or(&mut self) -> Result<TagExpr, TagExprError> {
+		let mut exprs = Vec::new();
+		exprs.push(self.parse_and()?);
+
+		while self.eat('|') {
+			exprs.push(self.parse_and()?);
+		}
+
+		Ok(if exprs.len() == 1 {
+			exprs.remove(0)
+		} else {
+			TagExpr::Or(exprs)
+		})
+	}
+
+	fn parse_and(&mut self) -> Result<TagExpr, TagExprError> {
+		let mut exprs = Vec::new();
+		exprs.push(self.parse_unary()?);
+
+		while self.eat('&') {
+			exprs.push(self.parse_unary()?);
+		}
+
+		Ok(if exprs.len() == 1 {
+			exprs.remove(0)
+		} else {
+			TagExpr::And(exprs)
+		})
+	}
+
+	fn parse_unary(&mut self) -> Result<TagExpr, TagExprError> {
+		if self.eat('!') {
+			let expr = self.nested(|parser| parser.parse_unary())?;
+			return Ok(TagExpr::Not(Box::new(expr)));
+		}
+
+		if self.eat('(') {
+			if let Some(expr) = self.parse_empty_list() {
+				return Ok(expr);
+			}
+
+			let expr = self.nested(|parser| parser.parse_or())?;
+			return match self.eat(')') {
+				true => Ok(expr),
+				false => Err(self.unexpected()),
+			};
+		}
+
+		self.parse_tag()
+	}
+
+	// every negation and parenthesis recurses, so the nesting is limited
+	fn nested<F>(&mut self, parse: F) -> Result<TagExpr, TagExprError>
+	where
+		F: FnOnce(&mut Self) -> Result<TagExpr, TagExprError>,
+	{
+		if self.depth == MAX_NESTING {
+			return Err(TagExprError::TooDeep(self.position));
+		}
+
+		self.depth += 1;
+		let expr = parse(self);
+		self.depth -= 1;
+		expr
+	}
+
+	// `(&)` or `(|)`, right after the opening parenthesis
+	fn parse_empty_list(&mut self) -> Option<TagExpr> {
+		let start = self.position;
+
+		let expr = if self.eat('&') {
+			TagExpr::And(Vec::new())
+		} else if self.eat('|') {
+			TagExpr::Or(Vec::new())
+		} else {
+			return None;
+		};
+
+		if self.eat(')') {
+			Some(expr)
+		} else {
+			self.position = start;
+			None
+		}
+	}
+
+	fn parse_tag(&mut self) -> Result<TagExpr, TagExprError> {
+		self.skip_whitespace();
+		if self.peek() == Some('\'') {
+			return self.parse_quoted_tag();
+		}
+		let start = self.position;
+
+		while let Some(c) = self.peek() {
+			if !is_tag_char(c) {
+				break;
+			}
+			self.position += c.len_utf8();
+		}
+
+		if start == self.position {
+			return Err(self.unexpected());
+		}
+
+		Ok(TagExpr::Tag(LayerTag::new(String::from(
+			&self.input[start..self.position],
+		))))
+	}
+
+	fn parse_quoted_tag(&mut self) -> Result<TagExpr, TagExprError> {
+		self.position += 1;
+		let mut name = String::new();
+
+		loop {
+			let c = self.peek().ok_or(TagExprError::UnexpectedEnd)?;
+			self.position += c.len_utf8();
+
+			if c == '\'' {
+				// a doubled quote stands for one quote in the name
+				if self.peek() != Some('\'') {
+					return Ok(TagExpr::Tag(LayerTag::new(name)));
+				}
+				self.position += 1;
+			}
+			name.push(c);
+		}
+	}
+
+	fn eat(&mut self, expected: char) -> bool {
+		self.skip_whitespace();
+
+		if self.peek() == Some(expected) {
+			self.position += expected.len_utf8();
+			true
+		} else {
+			false
+		}
+	}
+
+	fn skip_whitespace(&mut self) {
+		while let Some(c) = self.peek() {
+			if !c.is_whitespace() {
+				break;
+			}
+			self.position += c.len_utf8();
+		}
+	}
+
+	fn peek(&self) -> Option<char> {
+		self.input[self.position..].chars().next()
+	}
+
+	fn unexpected(&self) -> TagExprError {
+		match self.peek() {
+			Some(_) => TagExprError::UnexpectedChar(self.position),
+			None => TagExprError::UnexpectedEnd,
+		}
+	}
+}
+
+fn is_tag_char(c: char) -> bool {
+	!c.is_whitespace() && !matches!(c, '&' | '|' | '!' | '(' | ')')
+}
+
+// quoted unless it reads back as the same bare tag
+fn fmt_tag(f: &mut fmt::Formatter, tag: &str) -> fmt::Result {
+	if !tag.is_empty() && !tag.starts_with('\'') && tag.chars().all(is_tag_char) {
+		return write!(f, "{}", tag);
+	}
+
+	write!(f, "'")?;
+	for c in tag.chars() {
+		if c == '\'' {
+			write!(f, "'")?;
+		}
+		write!(f, "{}", c)?;
+	}
+	write!(f, "'")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use alloc::vec;
+
+	#[test]
+	fn parses_single_tag() {
+		assert_eq!(TagExpr::parse("fn"), Ok(tag("fn")));
+	}
+
+	#[test]
+	fn parses_with_precedence() {
+		assert_eq!(
+			TagExpr::parse("a | b & !c"),
+			Ok(TagExpr::Or(vec![
+				tag("a"),
+				TagExpr::And(vec![tag("b"), TagExpr::Not(Box::new(tag("c")))]),
+			]))
+		);
+	}
+
+	#[test]
+	fn parses_parentheses() {
+		assert_eq!(
+			TagExpr::parse("(game | vim) & caps"),
+			Ok(TagExpr::And(vec![
+				TagExpr::Or(vec![tag("game"), tag("vim")]),
+				tag("caps"),
+			]))
+		);
+	}
+
+	#[test]
+	fn rejects_invalid_expressions() {
+		assert_eq!(TagExpr::parse(""), Err(TagExprError::UnexpectedEnd));
+		assert_eq!(TagExpr::parse("a &"), Err(TagExprError::UnexpectedEnd));
+		assert_eq!(TagExpr::parse("(a | b"), Err(TagExprError::UnexpectedEnd));
+		assert_eq!(TagExpr::parse("a b"), Err(TagExprError::UnexpectedChar(2)));
+		assert_eq!(
+			TagExpr::parse("a & )"),
+			Err(TagExprError::UnexpectedChar(4))
+		);
+	}
+
+	#[test]
+	fn rejects_deep_nesting() {
+		let nested = "(".repeat(32) + "a" + &")".repeat(32);
+		assert!(TagExpr::parse(&nested).is_ok());
+
+		let too_deep = "(".repeat(33) + "a" + &")".repeat(33);
+		assert_eq!(TagExpr::parse(&too_deep), Err(TagExprError::TooDeep(33)));
+		assert_eq!(
+			TagExpr::parse(&"!".repeat(1000)),
+			Err(TagExprError::TooDeep(33))
+		);
+	}
+
+	#[test]
+	fn displays_in_parseable_form() {
+		for input in [
+			"fn & !shift",
+			"(game | vim) & caps",
+			"!(a & b) | c",
+			"a | b | c",
+		] {
+			let expr = TagExpr::parse(input).unwrap();
+			assert_eq!(expr.to_string(), input);
+		}
+	}
+
+	#[test]
+	fn evaluates_against_tag_list() {
+		let expr = TagExpr::parse("(game | vim) & caps & !shift").unwrap();
+		let mut tags = TagList::new();
+		assert!(!expr.evaluate(&tags));
+
+		tags.add_internal(LayerTag::new("caps".to_string()));
+		tags.set_external(vec![LayerTag::new("vim".to_string())]);
+		assert!(expr.evaluate(&tags));
+
+		tags.add_internal(LayerTag::new("shift".to_string()));
+		assert!(!expr.evaluate(&tags));
+	}
+
+	#[test]
+	fn compiled_expression_matches_evaluation() {
+		let expr = TagExpr::parse("(game | vim) & !shift").unwrap();
+		let mut tags = TagList::new();
+		let condition = expr.compile(&mut tags);
+
+		for active in [vec![], vec!["game"], vec!["vim", "shift"], vec!["shift"]] {
+			tags.set_external(
+				active
+					.into_iter()
+					.map(|name| LayerTag::new(name.to_string()))
+					.collect(),
+			);
+			assert_eq!(condition.evaluate(&tags), expr.evaluate(&tags));
+		}
+	}
+
+	#[test]
+	fn round_trips_through_json() {
+		let expr = TagExpr::parse("fn & !shift").unwrap();
+
+		let json = serde_json_core::to_string::<_, 64>(&expr).unwrap();
+		assert_eq!(json.as_str(), "\"fn & !shift\"");
+
+		let (parsed, _) = serde_json_core::from_str::<TagExpr>(&json).unwrap();
+		assert_eq!(parsed, expr);
+	}
+
+	#[test]
+	fn empty_operands_round_trip() {
+		for expr in [
+			TagExpr::And(vec![]),
+			TagExpr::Or(vec![]),
+			TagExpr::Not(Box::new(TagExpr::And(vec![]))),
+			TagExpr::And(vec![tag("a"), TagExpr::Or(vec![])]),
+		] {
+			let json = serde_json_core::to_string::<_, 64>(&expr).unwrap();
+			let (parsed, _) = serde_json_core::from_str::<TagExpr>(&json).unwrap();
+			assert_eq!(parsed, expr);
+		}
+
+		let tags = TagList::new();
+		assert!(TagExpr::parse("(&)").unwrap().evaluate(&tags));
+		assert!(!TagExpr::parse("( | )").unwrap().evaluate(&tags));
+	}
+
+	#[test]
+	fn tags_with_special_characters_round_trip() {
+		for name in ["caps lock", "a&b", "(x)", "!", "", "'", "it's", "'quoted'"] {
+			let expr = TagExpr::And(vec![tag(name), TagExpr::Not(Box::new(tag(name)))]);
+			assert_eq!(TagExpr::parse(&expr.to_string()), Ok(expr));
+
+			let json = serde_json_core::to_string::<_, 64>(&tag(name)).unwrap();
+			let (parsed, _) = serde_json_core::from_str::<TagExpr>(&json).unwrap();
+			assert_eq!(parsed, tag(name));
+		}
+
+		assert_eq!(tag("caps lock").to_string(), "'caps lock'");
+		assert_eq!(tag("it's").to_string(), "it's");
+		assert_eq!(TagExpr::parse("'a"), Err(TagExprError::UnexpectedEnd));
+	}
+
+	fn tag(name: &str) -> TagExpr {
+		TagExpr::Tag(LayerTag::new(name.to_string()))
+	}
+}